@@ -6,6 +6,12 @@ use futures::stream::{self, StreamExt};
 use redis::Commands;
 use s3::creds::Credentials;
 
+// Validation shared with the server
+#[path = "../../src/video_id.rs"]
+mod video_id;
+
+use video_id::VideoId;
+
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
@@ -49,7 +55,14 @@ async fn main() {
         async move {
             let now = std::time::Instant::now();
             let file_name = path.file_name().unwrap().to_str().unwrap();
-            let yt_id = file_name.split('.').next().unwrap();
+            let yt_id = match file_name.split('.').next().unwrap().parse::<VideoId>() {
+                Ok(yt_id) => yt_id,
+                Err(e) => {
+                    println!("Skipping {file_name}: {e}");
+                    return;
+                }
+            };
+            let yt_id = &*yt_id;
             let s3_key = s3_key(&path);
 
             // Read file content
@@ -0,0 +1,87 @@
+use crate::log::{self, LogType};
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{HeaderValue, Response},
+    middleware::Next,
+};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// How a request for a thumbnail was served, attached to the response for the
+/// access log
+#[derive(Clone, Copy)]
+pub struct CacheOutcome(pub &'static str);
+
+/// Use the request ID given by the client or a proxy, if it looks sane
+fn incoming_request_id(request: &Request) -> Option<String> {
+    let value = request.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid =
+        !value.is_empty() && value.len() <= 128 && value.chars().all(|c| c.is_ascii_graphic());
+    valid.then(|| value.to_string())
+}
+
+/// Assign an ID to every request, attach it to all log lines written while
+/// handling it, and write one access log line when it completes
+pub async fn access_log(request: Request, next: Next) -> Response<Body> {
+    let request_id =
+        incoming_request_id(&request).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let now = std::time::Instant::now();
+
+    let mut response = log::with_request_id(request_id.clone(), async {
+        let response = next.run(request).await;
+        let status = response.status().as_u16();
+        let bytes = response.body().size_hint().exact().unwrap_or(0);
+        let duration_ms = now.elapsed().as_millis();
+        let cache = response
+            .extensions()
+            .get::<CacheOutcome>()
+            .map_or("-", |outcome| outcome.0);
+        crate::log!(
+            "{method} {path} {status} {bytes}B {duration_ms}ms {cache}",
+            LogType::Info;
+            method = method.as_str(),
+            path = &path,
+            status = status as u64,
+            bytes = bytes,
+            duration_ms = duration_ms,
+            cache = cache,
+        );
+        response
+    })
+    .await;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_id(id: &str) -> Request {
+        Request::builder()
+            .header(REQUEST_ID_HEADER, id)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_incoming_request_id() {
+        assert_eq!(
+            incoming_request_id(&request_with_id("abc-123")),
+            Some("abc-123".to_string())
+        );
+        assert_eq!(incoming_request_id(&request_with_id("")), None);
+        assert_eq!(incoming_request_id(&request_with_id("has space")), None);
+        assert_eq!(
+            incoming_request_id(&request_with_id(&"a".repeat(129))),
+            None
+        );
+        assert_eq!(incoming_request_id(&Request::new(Body::empty())), None);
+    }
+}
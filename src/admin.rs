@@ -0,0 +1,458 @@
+use crate::{
+    AppState, apikeys, blocked_status, blocklist, cache_thumbnail, fetch_cached_quality,
+    fetch_from_cache, fetch_thumbnail, json_error, log, log::LogType, metadata, purge_thumbnail,
+    quality::Quality, s3_key, signing, storage, video_id::VideoId,
+};
+use axum::{
+    Extension, Json, Router,
+    body::Body,
+    extract::{OriginalUri, Path, Request, rejection::PathRejection},
+    http::{Response, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Routes for managing cached thumbnails, mounted under `/admin` when an
+/// admin token is configured
+pub fn router() -> Router {
+    Router::new()
+        .route("/thumbnails/{video_id}", delete(purge))
+        .route("/thumbnails/{video_id}/refetch", post(refetch))
+        .route("/thumbnails/{video_id}/pin", post(pin).delete(unpin))
+        .route("/blocklist", get(list_blocked))
+        .route("/blocklist/{video_id}", put(block).delete(unblock))
+        .route("/keys", post(create_key))
+        .route("/sign", post(sign))
+        .route("/keys/{id}", get(inspect_key).delete(revoke_key))
+        .route_layer(middleware::from_fn(require_token))
+}
+
+/// Compare tokens in constant time, so the expected token can't be guessed
+/// from response times
+fn tokens_match(expected: &str, provided: &str) -> bool {
+    let expected = Sha256::digest(expected.as_bytes());
+    let provided = Sha256::digest(provided.as_bytes());
+    expected
+        .iter()
+        .zip(provided.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Reject requests without `Authorization: Bearer <ADMIN_TOKEN>`
+async fn require_token(
+    Extension(state): Extension<AppState>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (state.config.admin.token.as_deref(), provided) {
+        (Some(expected), Some(provided)) if tokens_match(expected, provided) => {
+            next.run(request).await
+        }
+        _ => {
+            // The path seen here is relative to `/admin`
+            let path = request
+                .extensions()
+                .get::<OriginalUri>()
+                .map_or(request.uri().path(), |uri| uri.path())
+                .to_string();
+            log!(
+                "ADMIN: Rejected {} {path}",
+                LogType::Warning,
+                request.method();
+                path = &path,
+            );
+            json_error(StatusCode::UNAUTHORIZED, "Invalid admin token")
+        }
+    }
+}
+
+fn log_action(action: &str, video_id: &str, detail: &str) {
+    log!(
+        "ADMIN: {action} {video_id} - {detail}",
+        LogType::Info;
+        action = action,
+        video_id = video_id,
+    );
+}
+
+/// Delete every stored variant of a video
+async fn purge(
+    video_id: Result<Path<VideoId>, PathRejection>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    let Ok(Path(video_id)) = video_id else {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid video ID");
+    };
+    match purge_thumbnail(&state, &video_id).await {
+        Ok(deleted) => {
+            log_action("purge", &video_id, &format!("{deleted} objects deleted"));
+            Json(serde_json::json!({ "video_id": video_id, "deleted": deleted })).into_response()
+        }
+        Err(e) => {
+            log!(
+                "ERROR: Error purging {video_id}: {e}",
+                LogType::Error;
+                video_id = &video_id,
+            );
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error purging thumbnail")
+        }
+    }
+}
+
+/// Fetch the best available quality from YouTube again, replacing everything
+/// stored for the video. The existing entry is kept if nothing is found, or
+/// if the new one can't be stored.
+async fn refetch(
+    video_id: Result<Path<VideoId>, PathRejection>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    let Ok(Path(video_id)) = video_id else {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid video ID");
+    };
+    if let Some(status) = blocked_status(&state, &video_id).await {
+        return json_error(status, "Video is blocked");
+    }
+    for quality in state.config.qualities.iter() {
+        match fetch_thumbnail(&state, &video_id, quality).await {
+            Ok(data) => {
+                let previous = match fetch_cached_quality(&state.redis_pool, &video_id).await {
+                    Ok(previous) => previous,
+                    Err(_) => {
+                        return json_error(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Error reading thumbnail",
+                        );
+                    }
+                };
+                if cache_thumbnail(&state, &video_id, *quality, data, true)
+                    .await
+                    .is_err()
+                {
+                    return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error saving thumbnail");
+                }
+                // The new entry is in place, so leftovers are only logged
+                if let Err(e) = remove_old_variants(&state, &video_id, quality, previous).await {
+                    log!(
+                        "ERROR: Error removing old variants of {video_id}: {e}",
+                        LogType::Error;
+                        video_id = &video_id,
+                    );
+                }
+                log_action("refetch", &video_id, &quality.to_string());
+                return Json(serde_json::json!({ "video_id": video_id, "quality": quality }))
+                    .into_response();
+            }
+            Err(StatusCode::NOT_FOUND) => continue,
+            Err(_) => {
+                return json_error(
+                    StatusCode::BAD_GATEWAY,
+                    "Error fetching thumbnail from YouTube",
+                );
+            }
+        }
+    }
+    json_error(StatusCode::NOT_FOUND, "No thumbnail found on YouTube")
+}
+
+/// Delete every stored variant of a video other than the refetched
+/// `quality`, including the `previous` best quality, and drop any pin
+async fn remove_old_variants(
+    state: &AppState,
+    video_id: &str,
+    quality: &Quality,
+    previous: Option<Quality>,
+) -> anyhow::Result<()> {
+    let key = s3_key(video_id, quality);
+    let mut keys = metadata::get_variants(&state.redis_pool, video_id).await?;
+    if let Some(previous) = previous {
+        keys.push(s3_key(video_id, &previous));
+    }
+    keys.sort();
+    keys.dedup();
+    for old in keys.iter().filter(|old| **old != key) {
+        storage::delete_s3_object(&state.bucket, old).await?;
+        metadata::remove_variant(&state.redis_pool, video_id, old).await?;
+    }
+    metadata::unpin(&state.redis_pool, video_id).await
+}
+
+#[derive(Deserialize)]
+struct PinRequest {
+    /// File name of the quality, e.g. `sddefault.jpg`
+    quality: String,
+}
+
+/// Serve a specific quality by default, taken from the cache if stored or
+/// fetched from YouTube otherwise. The quality is stored as a variant, and
+/// the best available quality stays recorded as it is.
+async fn pin(
+    video_id: Result<Path<VideoId>, PathRejection>,
+    Extension(state): Extension<AppState>,
+    Json(request): Json<PinRequest>,
+) -> Response<Body> {
+    let Ok(Path(video_id)) = video_id else {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid video ID");
+    };
+    let Some(quality) = Quality::from_file_name(&request.quality) else {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid quality");
+    };
+    if !state.config.qualities.contains(&quality) {
+        return json_error(StatusCode::BAD_REQUEST, "Quality is not configured");
+    }
+    if let Some(status) = blocked_status(&state, &video_id).await {
+        return json_error(status, "Video is blocked");
+    }
+    let data = match fetch_from_cache(&state.bucket, &video_id, &quality).await {
+        Some(data) => data,
+        None => match fetch_thumbnail(&state, &video_id, &quality).await {
+            Ok(data) => data,
+            Err(StatusCode::NOT_FOUND) => {
+                return json_error(StatusCode::NOT_FOUND, "Quality is not available");
+            }
+            Err(_) => {
+                return json_error(
+                    StatusCode::BAD_GATEWAY,
+                    "Error fetching thumbnail from YouTube",
+                );
+            }
+        },
+    };
+    // Stored before it is pinned, so a pin always refers to a stored image
+    let result = match cache_thumbnail(&state, &video_id, quality, data, false).await {
+        Ok(()) => metadata::pin(&state.redis_pool, &video_id, &quality).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        log!(
+            "ERROR: Error pinning {video_id}: {e}",
+            LogType::Error;
+            video_id = &video_id,
+        );
+        return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error pinning thumbnail");
+    }
+    log_action("pin", &video_id, &quality.to_string());
+    Json(serde_json::json!({ "video_id": video_id, "quality": quality })).into_response()
+}
+
+/// Serve the best available quality by default again
+async fn unpin(
+    video_id: Result<Path<VideoId>, PathRejection>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    let Ok(Path(video_id)) = video_id else {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid video ID");
+    };
+    match metadata::unpin(&state.redis_pool, &video_id).await {
+        Ok(()) => {
+            log_action("unpin", &video_id, "best available");
+            Json(serde_json::json!({ "video_id": video_id })).into_response()
+        }
+        Err(e) => {
+            log!(
+                "ERROR: Error unpinning {video_id}: {e}",
+                LogType::Error;
+                video_id = &video_id,
+            );
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error unpinning thumbnail",
+            )
+        }
+    }
+}
+
+async fn list_blocked(Extension(state): Extension<AppState>) -> Response<Body> {
+    match blocklist::list(&state.redis_pool).await {
+        Ok(video_ids) => Json(video_ids).into_response(),
+        Err(e) => {
+            log!("ERROR: Error reading blocklist: {e}", LogType::Error);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error reading blocklist")
+        }
+    }
+}
+
+/// Stop serving a video and delete everything stored for it
+async fn block(
+    video_id: Result<Path<VideoId>, PathRejection>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    let Ok(Path(video_id)) = video_id else {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid video ID");
+    };
+    match blocklist::block(&state, &video_id).await {
+        Ok(deleted) => {
+            log_action("block", &video_id, &format!("{deleted} objects deleted"));
+            Json(serde_json::json!({ "video_id": video_id, "deleted": deleted })).into_response()
+        }
+        Err(e) => {
+            log!(
+                "ERROR: Error blocking {video_id}: {e}",
+                LogType::Error;
+                video_id = &video_id,
+            );
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error blocking video")
+        }
+    }
+}
+
+async fn unblock(
+    video_id: Result<Path<VideoId>, PathRejection>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    let Ok(Path(video_id)) = video_id else {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid video ID");
+    };
+    match blocklist::unblock(&state.redis_pool, &video_id).await {
+        Ok(()) => {
+            log_action("unblock", &video_id, "removed from blocklist");
+            Json(serde_json::json!({ "video_id": video_id })).into_response()
+        }
+        Err(e) => {
+            log!(
+                "ERROR: Error unblocking {video_id}: {e}",
+                LogType::Error;
+                video_id = &video_id,
+            );
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error unblocking video")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateKeyRequest {
+    /// Who the key is for, e.g. the partner team
+    name: String,
+    /// Cache misses allowed per day, no limit if unset
+    #[serde(default)]
+    daily_quota: u64,
+}
+
+/// Create an API key. The key itself is only returned here.
+async fn create_key(
+    Extension(state): Extension<AppState>,
+    Json(request): Json<CreateKeyRequest>,
+) -> Response<Body> {
+    match apikeys::create(&state.redis_pool, &request.name, request.daily_quota).await {
+        Ok((api_key, key)) => {
+            log!(
+                "ADMIN: create key {} - {}",
+                LogType::Info,
+                api_key.id,
+                api_key.name;
+                action = "create key",
+            );
+            let mut body = serde_json::to_value(&api_key).unwrap_or_default();
+            body["key"] = key.into();
+            (StatusCode::CREATED, Json(body)).into_response()
+        }
+        Err(e) => {
+            log!("ERROR: Error creating API key: {e}", LogType::Error);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error creating API key")
+        }
+    }
+}
+
+/// Describe an API key with its usage by day
+async fn inspect_key(
+    Path(id): Path<String>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    let result = tokio::try_join!(
+        apikeys::get(&state.redis_pool, &id),
+        apikeys::usage(&state.redis_pool, &id),
+    );
+    match result {
+        Ok((Some(api_key), usage)) => {
+            let mut body = serde_json::to_value(&api_key).unwrap_or_default();
+            body["usage"] = serde_json::to_value(usage).unwrap_or_default();
+            Json(body).into_response()
+        }
+        Ok((None, _)) => json_error(StatusCode::NOT_FOUND, "Unknown API key"),
+        Err(e) => {
+            log!("ERROR: Error reading API key {id}: {e}", LogType::Error);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error reading API key")
+        }
+    }
+}
+
+async fn revoke_key(
+    Path(id): Path<String>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    match apikeys::get(&state.redis_pool, &id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Unknown API key"),
+        Err(e) => {
+            log!("ERROR: Error reading API key {id}: {e}", LogType::Error);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error reading API key");
+        }
+    }
+    match apikeys::revoke(&state.redis_pool, &id).await {
+        Ok(()) => {
+            log!("ADMIN: revoke key {id}", LogType::Info; action = "revoke key");
+            Json(serde_json::json!({ "id": id, "revoked": true })).into_response()
+        }
+        Err(e) => {
+            log!("ERROR: Error revoking API key {id}: {e}", LogType::Error);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error revoking API key")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SignRequest {
+    /// Path to sign, e.g. `/aGb3AlQrN9E`
+    path: String,
+    /// Query parameters to sign, e.g. `{"max": "sd"}`
+    #[serde(default)]
+    params: BTreeMap<String, String>,
+    /// Seconds until the URL expires, never if unset
+    expires_in: Option<i64>,
+}
+
+/// Sign a URL for a variant other than the default
+async fn sign(
+    Extension(state): Extension<AppState>,
+    Json(request): Json<SignRequest>,
+) -> Response<Body> {
+    let Some(secret) = &state.config.signing.secret else {
+        return json_error(StatusCode::NOT_FOUND, "URL signing is not configured");
+    };
+    let reserved = [signing::SIGNATURE_PARAM, signing::EXPIRES_PARAM];
+    if request
+        .params
+        .keys()
+        .any(|name| reserved.contains(&name.as_str()))
+    {
+        return json_error(StatusCode::BAD_REQUEST, "Reserved parameter");
+    }
+    let expires = request
+        .expires_in
+        .map(|seconds| chrono::Utc::now().timestamp() + seconds);
+    let params = request.params.into_iter().collect();
+    let url = signing::signed_url(secret, &request.path, params, expires);
+    Json(serde_json::json!({ "url": url })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("s3cret", "s3cret"));
+        assert!(!tokens_match("s3cret", "s3cret "));
+        assert!(!tokens_match("s3cret", ""));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{AppState, json_error, log, log::LogType, storage};
+use anyhow::Result;
+use axum::{
+    Extension,
+    body::Body,
+    extract::{Query, Request},
+    http::{Response, StatusCode},
+    middleware::Next,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+const API_KEY_HEADER: &str = "X-Api-Key";
+const API_KEY_PARAM: &str = "key";
+
+/// Length of the key ID, a prefix of the hash of the key
+const ID_LENGTH: usize = 16;
+
+/// Redis hash describing an API key. Only a hash of the key is stored.
+fn key_key(id: &str) -> String {
+    format!("apikey:{id}")
+}
+
+/// Redis hash with per-day usage counters of an API key
+fn usage_key(id: &str) -> String {
+    format!("usage:{id}")
+}
+
+/// Redis counter of the cache misses of an API key on one day, checked
+/// against its quota
+fn misses_key(id: &str, day: &str) -> String {
+    format!("misses:{id}:{day}")
+}
+
+/// How long a day's miss counter is kept, longer than the day itself so it
+/// outlives clock differences between instances
+const MISSES_TTL: std::time::Duration = std::time::Duration::from_secs(2 * 24 * 60 * 60);
+
+fn today() -> String {
+    chrono::Utc::now().format("%Y-%m-%d").to_string()
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+#[derive(Clone, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// Cache misses allowed per day (UTC), 0 for no limit
+    pub daily_quota: u64,
+    pub created: String,
+    pub revoked: Option<String>,
+}
+
+impl ApiKey {
+    fn from_fields(id: &str, fields: &HashMap<String, String>) -> Option<Self> {
+        Some(ApiKey {
+            id: id.to_string(),
+            name: fields.get("name")?.clone(),
+            daily_quota: fields.get("daily_quota")?.parse().ok()?,
+            created: fields.get("created")?.clone(),
+            revoked: fields.get("revoked").cloned(),
+        })
+    }
+}
+
+/// Create a key, returning it and the plain key, which is not stored
+pub async fn create(
+    pool: &storage::RedisPool,
+    name: &str,
+    daily_quota: u64,
+) -> Result<(ApiKey, String)> {
+    let key = format!("thumbs_{}", uuid::Uuid::new_v4().simple());
+    let hash = hash(&key);
+    let id = hash[..ID_LENGTH].to_string();
+    let created = chrono::Utc::now().to_rfc3339();
+    let fields = [
+        ("hash", hash.clone()),
+        ("name", name.to_string()),
+        ("daily_quota", daily_quota.to_string()),
+        ("created", created.clone()),
+    ];
+    storage::put_redis_hash(pool, &key_key(&id), &fields).await?;
+    let api_key = ApiKey {
+        id,
+        name: name.to_string(),
+        daily_quota,
+        created,
+        revoked: None,
+    };
+    Ok((api_key, key))
+}
+
+pub async fn get(pool: &storage::RedisPool, id: &str) -> Result<Option<ApiKey>> {
+    let fields = storage::get_redis_hash(pool, &key_key(id)).await?;
+    Ok(ApiKey::from_fields(id, &fields))
+}
+
+/// Find the key matching a plain key given by a client
+async fn lookup(pool: &storage::RedisPool, key: &str) -> Result<Option<ApiKey>> {
+    let hash = hash(key);
+    let id = &hash[..ID_LENGTH];
+    let fields = storage::get_redis_hash(pool, &key_key(id)).await?;
+    if fields.get("hash") != Some(&hash) {
+        return Ok(None);
+    }
+    Ok(ApiKey::from_fields(id, &fields))
+}
+
+pub async fn revoke(pool: &storage::RedisPool, id: &str) -> Result<()> {
+    let revoked = chrono::Utc::now().to_rfc3339();
+    storage::put_redis_hash(pool, &key_key(id), &[("revoked", revoked)]).await
+}
+
+#[derive(Serialize, Default)]
+pub struct Usage {
+    requests: u64,
+    misses: u64,
+}
+
+/// Usage counters of a key by day
+pub async fn usage(pool: &storage::RedisPool, id: &str) -> Result<BTreeMap<String, Usage>> {
+    let fields = storage::get_redis_hash(pool, &usage_key(id)).await?;
+    let mut usage = BTreeMap::<String, Usage>::new();
+    for (field, value) in fields {
+        let Some((day, counter)) = field.split_once(':') else {
+            continue;
+        };
+        let value = value.parse().unwrap_or(0);
+        let entry = usage.entry(day.to_string()).or_default();
+        match counter {
+            "requests" => entry.requests = value,
+            "misses" => entry.misses = value,
+            _ => {}
+        }
+    }
+    Ok(usage)
+}
+
+async fn record(pool: &storage::RedisPool, id: &str, counter: &str) -> Result<()> {
+    let field = format!("{}:{counter}", today());
+    storage::increment_redis_hash(pool, &usage_key(id), &[(field, 1)]).await
+}
+
+/// Whether a key has used up its quota of cache misses for today
+pub async fn over_quota(pool: &storage::RedisPool, key: &ApiKey) -> Result<bool> {
+    if key.daily_quota == 0 {
+        return Ok(false);
+    }
+    let misses = storage::get_redis_object(pool, &misses_key(&key.id, &today())).await?;
+    let misses = misses.and_then(|v| v.parse().ok()).unwrap_or(0);
+    Ok(misses >= key.daily_quota)
+}
+
+/// Count a cache miss against a key, or return false if its quota is used up.
+/// The counter is incremented before it is compared, so concurrent misses
+/// can't overshoot the quota.
+pub async fn take_miss(pool: &storage::RedisPool, key: &ApiKey) -> Result<bool> {
+    if key.daily_quota > 0 {
+        let counter = misses_key(&key.id, &today());
+        let misses = storage::increment_redis_counter(pool, &counter, 1, MISSES_TTL).await?;
+        if misses > key.daily_quota as i64 {
+            // Give back the miss, so the counter matches what was served
+            storage::increment_redis_counter(pool, &counter, -1, MISSES_TTL).await?;
+            return Ok(false);
+        }
+    }
+    // The miss is already counted against the quota, so failing to record
+    // it for reporting doesn't fail the request
+    if let Err(e) = record(pool, &key.id, "misses").await {
+        log!(
+            "ERROR: Error recording a miss for API key {}: {e}",
+            LogType::Error,
+            key.id,
+        );
+    }
+    Ok(true)
+}
+
+/// Time until quotas reset at midnight UTC
+pub fn until_reset() -> std::time::Duration {
+    let now = chrono::Utc::now();
+    let midnight = (now.date_naive() + chrono::Days::new(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    (midnight - now).to_std().unwrap_or_default()
+}
+
+fn provided_key(request: &Request) -> Option<String> {
+    if let Some(value) = request.headers().get(API_KEY_HEADER) {
+        return value.to_str().ok().map(str::to_string);
+    }
+    let Query(mut params) = Query::<HashMap<String, String>>::try_from_uri(request.uri()).ok()?;
+    params.remove(API_KEY_PARAM)
+}
+
+/// Identify the caller by the API key in the `X-Api-Key` header or `key`
+/// query parameter, and count the request against it. The key is attached
+/// to the request for quota checks on cache misses.
+pub async fn authenticate(
+    Extension(state): Extension<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response<Body> {
+    let Some(key) = provided_key(&request) else {
+        if state.config.api_keys.anonymous {
+            return next.run(request).await;
+        }
+        return json_error(StatusCode::UNAUTHORIZED, "An API key is required");
+    };
+    let api_key = match lookup(&state.redis_pool, &key).await {
+        Ok(Some(api_key)) if api_key.revoked.is_none() => api_key,
+        Ok(_) => {
+            log!("UNAUTHORIZED: Invalid or revoked API key", LogType::Warning);
+            return json_error(StatusCode::UNAUTHORIZED, "Invalid API key");
+        }
+        Err(e) => {
+            log!("ERROR: Error reading API key: {e}", LogType::Error);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error reading API key");
+        }
+    };
+    if let Err(e) = record(&state.redis_pool, &api_key.id, "requests").await {
+        log!(
+            "ERROR: Error recording usage of API key {}: {e}",
+            LogType::Error,
+            api_key.id,
+        );
+    }
+    request.extensions_mut().insert(api_key);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provided_key() {
+        let request = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        assert_eq!(
+            provided_key(&request("/aGb3AlQrN9E?max=sd&key=thumbs_abc")),
+            Some("thumbs_abc".to_string())
+        );
+        assert_eq!(provided_key(&request("/aGb3AlQrN9E")), None);
+        let mut with_header = request("/aGb3AlQrN9E?key=other");
+        with_header
+            .headers_mut()
+            .insert(API_KEY_HEADER, "thumbs_def".parse().unwrap());
+        assert_eq!(provided_key(&with_header), Some("thumbs_def".to_string()));
+    }
+}
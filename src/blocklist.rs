@@ -0,0 +1,81 @@
+use crate::{AppState, log, log::LogType, purge_thumbnail, storage, video_id::VideoId};
+use anyhow::Result;
+
+/// Redis set with the IDs of videos whose thumbnails must not be served
+const BLOCKLIST_KEY: &str = "blocklist";
+
+pub async fn is_blocked(pool: &storage::RedisPool, video_id: &str) -> Result<bool> {
+    storage::is_redis_set_member(pool, BLOCKLIST_KEY, video_id).await
+}
+
+pub async fn list(pool: &storage::RedisPool) -> Result<Vec<String>> {
+    let mut video_ids = storage::get_redis_set_members(pool, BLOCKLIST_KEY).await?;
+    video_ids.sort();
+    Ok(video_ids)
+}
+
+/// Stop serving a video and delete everything stored for it, returning the
+/// number of objects deleted
+pub async fn block(state: &AppState, video_id: &VideoId) -> Result<usize> {
+    // Blocked first, so a concurrent cache write either skips the video or
+    // removes it again once written
+    storage::add_redis_set_member(&state.redis_pool, BLOCKLIST_KEY, video_id).await?;
+    purge_thumbnail(state, video_id).await
+}
+
+pub async fn unblock(pool: &storage::RedisPool, video_id: &str) -> Result<()> {
+    storage::remove_redis_set_member(pool, BLOCKLIST_KEY, video_id).await
+}
+
+/// Video IDs in a blocklist file, one per line. Blank lines and lines
+/// starting with `#` are ignored.
+fn parse_file(content: &str) -> Vec<&str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+/// Block every video listed in a file, logging entries that fail
+pub async fn load_file(state: &AppState, path: &str) {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) => {
+            log!("ERROR: Error reading blocklist {path}: {e}", LogType::Error);
+            return;
+        }
+    };
+    let video_ids = parse_file(&content);
+    let mut blocked = 0;
+    for video_id in &video_ids {
+        let result = match video_id.parse() {
+            Ok(video_id) => block(state, &video_id).await,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(_) => blocked += 1,
+            Err(e) => log!(
+                "ERROR: Error blocking {video_id} from {path}: {e}",
+                LogType::Error;
+                video_id = *video_id,
+            ),
+        }
+    }
+    log!(
+        "Blocked {blocked} of {} videos listed in {path}",
+        LogType::Info,
+        video_ids.len(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file() {
+        let content = "# Takedowns\naGb3AlQrN9E\n\n  VLM5ECY07nw  \n#dQw4w9WgXcQ\n";
+        assert_eq!(parse_file(content), vec!["aGb3AlQrN9E", "VLM5ECY07nw"]);
+    }
+}
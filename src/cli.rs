@@ -0,0 +1,216 @@
+use crate::{
+    AppState, ThumbnailParams, blocked_status, fetch_cached_quality, fetch_from_cache,
+    log::LogType,
+    metadata::{self, ImageInfo},
+    purge_thumbnail, resolve_thumbnail, signing, stats, storage, thumbnail_metadata,
+    video_id::VideoId,
+};
+use anyhow::{Result, anyhow, bail};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about = "Proxy and cache for YouTube thumbnails")]
+pub struct Cli {
+    /// TOML configuration file, overridden by environment variables
+    #[arg(long, global = true, env = "CONFIG_FILE")]
+    pub config: Option<String>,
+    /// Print the effective configuration with secrets redacted and exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (default)
+    Serve,
+    /// Resolve the thumbnail of a video like the server does, filling the
+    /// cache on a miss, and print its metadata
+    Get {
+        video_id: VideoId,
+        /// Largest resolution to consider, e.g. `sddefault`
+        #[arg(long)]
+        max: Option<String>,
+        /// Smallest resolution to consider, e.g. `sddefault`
+        #[arg(long)]
+        min: Option<String>,
+        /// Write the image to this file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print the cached entries per quality. Hits, misses and latency are
+    /// counted by the server process, see `/stats`.
+    Stats {
+        /// Recount the cached entries first, replacing the maintained
+        /// counters. Scans every key, so only needed once for entries cached
+        /// before the counters existed.
+        #[arg(long)]
+        rebuild: bool,
+    },
+    /// Check that cached thumbnails exist in S3 and match their recorded
+    /// checksum. Checks every cached video if no IDs are given.
+    Verify { video_ids: Vec<VideoId> },
+    /// Delete every stored variant of a video
+    Purge { video_id: VideoId },
+    /// Print a signed URL for a variant other than the default
+    Sign {
+        /// Path to sign, e.g. `/aGb3AlQrN9E`
+        path: String,
+        /// Largest resolution to consider, e.g. `sddefault`
+        #[arg(long)]
+        max: Option<String>,
+        /// Smallest resolution to consider, e.g. `sddefault`
+        #[arg(long)]
+        min: Option<String>,
+        /// Seconds until the URL expires, never if unset
+        #[arg(long)]
+        expires_in: Option<i64>,
+    },
+}
+
+/// Run a command other than `serve`
+pub async fn run(command: Command, state: &AppState) -> Result<()> {
+    match command {
+        Command::Serve => unreachable!("The server is started from main"),
+        Command::Get {
+            video_id,
+            max,
+            min,
+            output,
+        } => get(state, &video_id, ThumbnailParams { max, min }, output).await,
+        Command::Stats { rebuild } => {
+            if rebuild {
+                let entries = stats::rebuild(&state.redis_pool, &state.bucket).await?;
+                crate::log!(
+                    "Recounted {entries} cached entries",
+                    LogType::Info;
+                    count = entries,
+                );
+            }
+            let report = stats::report(&state.redis_pool, None).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Command::Verify { video_ids } => verify(state, video_ids).await,
+        Command::Purge { video_id } => {
+            let deleted = purge_thumbnail(state, &video_id).await?;
+            println!("Purged {video_id}: {deleted} objects deleted");
+            Ok(())
+        }
+        Command::Sign {
+            path,
+            max,
+            min,
+            expires_in,
+        } => {
+            let Some(secret) = &state.config.signing.secret else {
+                bail!("SIGNING_SECRET is not set");
+            };
+            let params = [("max", max), ("min", min)]
+                .into_iter()
+                .filter_map(|(name, value)| Some((name.to_string(), value?)))
+                .collect();
+            let expires = expires_in.map(|seconds| chrono::Utc::now().timestamp() + seconds);
+            println!("{}", signing::signed_url(secret, &path, params, expires));
+            Ok(())
+        }
+    }
+}
+
+async fn get(
+    state: &AppState,
+    video_id: &VideoId,
+    params: ThumbnailParams,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    if let Some(status) = blocked_status(state, video_id).await {
+        bail!("{video_id} can't be fetched: {status}");
+    }
+    let qualities = params
+        .qualities(&state.config.qualities)
+        .map_err(|e| anyhow!(e))?;
+    let thumbnail = resolve_thumbnail(state, video_id, &qualities, None, None)
+        .await
+        .map_err(|status| anyhow!("No thumbnail found for {video_id}: {status}"))?;
+    if let Some(output) = output {
+        std::fs::write(&output, &thumbnail.data)?;
+    }
+
+    // Metadata is recorded by the cache write started on a miss
+    state.cache_writes.close();
+    state.cache_writes.wait().await;
+    let metadata = thumbnail_metadata(state, video_id)
+        .await
+        .map_err(|(_, message)| anyhow!("{message}"))?;
+    println!("{}", serde_json::to_string_pretty(&metadata)?);
+    Ok(())
+}
+
+async fn verify(state: &AppState, video_ids: Vec<VideoId>) -> Result<()> {
+    let video_ids = match video_ids.is_empty() {
+        true => cached_video_ids(state).await?,
+        false => video_ids,
+    };
+    let mut failed = 0;
+    for video_id in &video_ids {
+        match verify_thumbnail(state, video_id).await {
+            Ok(quality) => println!("{video_id} ok {quality}"),
+            Err(e) => {
+                println!("{video_id} failed: {e}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{failed} of {} thumbnails failed verification",
+            video_ids.len()
+        );
+    }
+    crate::log!(
+        "Verified {} thumbnails",
+        LogType::Info,
+        video_ids.len();
+        count = video_ids.len(),
+    );
+    Ok(())
+}
+
+/// Check the best stored thumbnail of a video, refreshing its metadata if
+/// it is intact
+async fn verify_thumbnail(state: &AppState, video_id: &VideoId) -> Result<String> {
+    let quality = fetch_cached_quality(&state.redis_pool, video_id)
+        .await?
+        .ok_or(anyhow!("not cached"))?;
+    let data = fetch_from_cache(&state.bucket, video_id, &quality)
+        .await
+        .ok_or(anyhow!("{quality} is missing from S3"))?;
+    let info = ImageInfo::from_data(&data);
+    let fields = metadata::get_metadata(&state.redis_pool, video_id).await?;
+    if let Some(sha256) = fields.get("sha256")
+        && *sha256 != info.sha256
+    {
+        bail!("{quality} does not match its recorded checksum");
+    }
+    metadata::save_metadata(&state.redis_pool, video_id, &info).await?;
+    Ok(quality.file_name())
+}
+
+async fn cached_video_ids(state: &AppState) -> Result<Vec<VideoId>> {
+    let mut video_ids = vec![];
+    let mut cursor = 0;
+    loop {
+        let (next, keys) =
+            storage::scan_video_ids(&state.redis_pool, cursor, "*", storage::SCAN_COUNT).await?;
+        video_ids.extend(keys);
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    video_ids.sort();
+    video_ids.dedup();
+    Ok(video_ids)
+}
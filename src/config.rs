@@ -0,0 +1,751 @@
+use crate::{SUPPORTED_QUALITIES, log::LogConfig, quality::Quality};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{fmt, net::IpAddr, str::FromStr, time::Duration};
+
+/// Settings read from an optional TOML file, overridden by environment
+/// variables
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    /// Address the server listens on (`BIND_ADDRESS`)
+    pub bind_address: String,
+    /// Qualities to look for, in order of preference (`QUALITIES`)
+    pub qualities: Vec<Quality>,
+    pub redis: RedisConfig,
+    pub s3: S3Config,
+    pub timeouts: Timeouts,
+    pub cache: CachePolicy,
+    pub admin: AdminConfig,
+    pub blocklist: BlocklistConfig,
+    pub rate_limit: RateLimitConfig,
+    pub api_keys: ApiKeyConfig,
+    pub signing: SigningConfig,
+    pub hotlink: HotlinkConfig,
+    pub log: LogSettings,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RedisConfig {
+    /// `REDIS_URL`
+    pub url: String,
+    /// Largest number of connections in the pool (`REDIS_POOL_SIZE`)
+    pub pool_size: u32,
+    /// Seconds to wait for a connection from the pool
+    /// (`REDIS_CONNECTION_TIMEOUT`)
+    pub connection_timeout: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Use path-style URLs, needed for MinIO in local development
+    pub path_style: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Timeouts {
+    /// Seconds to wait for a thumbnail from YouTube (`UPSTREAM_TIMEOUT`)
+    pub upstream: u64,
+    /// Seconds given to in-flight requests and cache writes on shutdown
+    /// (`DRAIN_TIMEOUT`)
+    pub drain: u64,
+    /// Seconds a request to YouTube may wait for a free slot
+    /// (`UPSTREAM_QUEUE_TIMEOUT`)
+    pub upstream_queue: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CachePolicy {
+    /// Store variants other than the best available quality, requested with
+    /// `max` or `min` (`CACHE_STORE_VARIANTS`)
+    pub store_variants: bool,
+    /// `max-age` of the `Cache-Control` header on thumbnails, not sent if
+    /// unset (`CACHE_MAX_AGE`)
+    pub max_age: Option<u64>,
+    /// Largest number of IDs in a batch request (`BATCH_MAX_IDS`)
+    pub batch_max_ids: usize,
+    /// Number of IDs in a batch request resolved at the same time
+    /// (`BATCH_CONCURRENCY`)
+    pub batch_concurrency: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdminConfig {
+    /// Bearer token for the admin routes, which are disabled if unset
+    /// (`ADMIN_TOKEN`)
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlocklistConfig {
+    /// Status returned with the fallback image for blocked videos
+    /// (`BLOCKLIST_STATUS`)
+    pub status: u16,
+    /// File with video IDs to block at startup, one per line
+    /// (`BLOCKLIST_FILE`)
+    pub file: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitConfig {
+    /// Cache misses allowed per client IP per second on average, 0 to
+    /// disable. Off by default, so clients are only held back by the
+    /// upstream limit until this is set (`RATE_LIMIT_PER_IP`)
+    pub per_ip_per_second: f64,
+    /// Cache misses a client IP may make in a burst (`RATE_LIMIT_BURST`)
+    pub per_ip_burst: f64,
+    /// Requests to YouTube allowed per second in total, 0 to disable
+    /// (`UPSTREAM_RATE_LIMIT`)
+    pub upstream_per_second: f64,
+    /// Requests to YouTube in flight at once (`UPSTREAM_CONCURRENCY`)
+    pub upstream_concurrency: usize,
+    /// Proxies whose `X-Forwarded-For` header is trusted, as addresses or
+    /// CIDR ranges (`TRUSTED_PROXIES`)
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKeyConfig {
+    /// Serve requests without an API key (`ALLOW_ANONYMOUS`)
+    pub anonymous: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SigningConfig {
+    /// Secret for signing URLs. If set, requests for variants other than the
+    /// default must be signed (`SIGNING_SECRET`)
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HotlinkConfig {
+    /// Origins allowed by CORS, any if empty (`CORS_ORIGINS`)
+    pub cors_origins: Vec<String>,
+    /// Hosts that may embed thumbnails, like `example.com` or
+    /// `*.example.com`, any if empty (`HOTLINK_ALLOWED_HOSTS`)
+    pub allowed_hosts: Vec<String>,
+    /// Serve requests without a `Referer` or `Origin` header
+    /// (`HOTLINK_ALLOW_MISSING`)
+    pub allow_missing: bool,
+    /// Status returned with the fallback image for other hosts, 406 by
+    /// default to tell it apart from a failed signature check
+    /// (`HOTLINK_STATUS`)
+    pub status: u16,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogSettings {
+    /// `human` or `json` (`LOG_FORMAT`)
+    pub format: String,
+    /// Default level and per-module overrides, e.g. `info,storage=debug`
+    /// (`LOG_LEVEL`)
+    pub level: String,
+}
+
+/// Layout of the configuration file, where every setting is optional
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind_address: Option<String>,
+    qualities: Option<Vec<String>>,
+    redis: FileRedis,
+    s3: FileS3,
+    timeouts: FileTimeouts,
+    cache: FileCache,
+    admin: FileAdmin,
+    blocklist: FileBlocklist,
+    rate_limit: FileRateLimit,
+    api_keys: FileApiKeys,
+    signing: FileSigning,
+    hotlink: FileHotlink,
+    log: FileLog,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileRedis {
+    url: Option<String>,
+    pool_size: Option<u32>,
+    connection_timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileS3 {
+    endpoint: Option<String>,
+    region: Option<String>,
+    bucket: Option<String>,
+    access_key: Option<String>,
+    secret_key: Option<String>,
+    path_style: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileTimeouts {
+    upstream: Option<u64>,
+    drain: Option<u64>,
+    upstream_queue: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileCache {
+    store_variants: Option<bool>,
+    max_age: Option<u64>,
+    batch_max_ids: Option<usize>,
+    batch_concurrency: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileAdmin {
+    token: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileBlocklist {
+    status: Option<u16>,
+    file: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileRateLimit {
+    per_ip_per_second: Option<f64>,
+    per_ip_burst: Option<f64>,
+    upstream_per_second: Option<f64>,
+    upstream_concurrency: Option<usize>,
+    trusted_proxies: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileApiKeys {
+    anonymous: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileSigning {
+    secret: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileHotlink {
+    cors_origins: Option<Vec<String>>,
+    allowed_hosts: Option<Vec<String>>,
+    allow_missing: Option<bool>,
+    status: Option<u16>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileLog {
+    format: Option<String>,
+    level: Option<String>,
+}
+
+/// Every problem found while reading the configuration
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration: {}", self.0.join("; "))
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Resolves each setting from the environment, then the file, then a
+/// default, collecting errors along the way
+struct Loader<F: Fn(&str) -> Option<String>> {
+    var: F,
+    errors: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Loader<F> {
+    fn env(&self, name: &str) -> Option<String> {
+        (self.var)(name).filter(|v| !v.is_empty())
+    }
+
+    fn required(&mut self, name: &str, file: Option<String>) -> String {
+        match self.env(name).or(file) {
+            Some(value) => value,
+            None => {
+                self.errors.push(format!("{name} is not set"));
+                String::new()
+            }
+        }
+    }
+
+    /// A comma separated list in the environment, or a list in the file
+    fn list(&self, name: &str, file: Option<Vec<String>>) -> Vec<String> {
+        match self.env(name) {
+            Some(value) => value.split(',').map(|v| v.trim().to_string()).collect(),
+            None => file.unwrap_or_default(),
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, file: Option<T>) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        match self.env(name) {
+            Some(value) => match value.parse() {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    self.errors.push(format!("{name} is invalid: {value}: {e}"));
+                    None
+                }
+            },
+            None => file,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from an optional TOML file and the environment
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigErrors> {
+        let file = match path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(content) => Some(content),
+                Err(e) => return Err(ConfigErrors(vec![format!("Error reading {path}: {e}")])),
+            },
+            None => None,
+        };
+        Config::from_sources(file.as_deref(), |name| std::env::var(name).ok())
+    }
+
+    fn from_sources(
+        file: Option<&str>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigErrors> {
+        let file = match file.map(toml::from_str::<FileConfig>) {
+            Some(Ok(file)) => file,
+            Some(Err(e)) => return Err(ConfigErrors(vec![format!("Invalid config file: {e}")])),
+            None => FileConfig::default(),
+        };
+        let mut loader = Loader {
+            var,
+            errors: vec![],
+        };
+
+        let bind_address = loader
+            .env("BIND_ADDRESS")
+            .or(file.bind_address)
+            .unwrap_or_else(|| "0.0.0.0:2342".to_string());
+        if bind_address.parse::<std::net::SocketAddr>().is_err() {
+            loader.errors.push(format!(
+                "BIND_ADDRESS is not a valid address: {bind_address}"
+            ));
+        }
+
+        let qualities = match loader.env("QUALITIES") {
+            Some(value) => Some(value.split(',').map(|q| q.trim().to_string()).collect()),
+            None => file.qualities,
+        };
+        let qualities = match qualities {
+            Some(names) => match parse_qualities(&names) {
+                Ok(qualities) => qualities,
+                Err(e) => {
+                    loader.errors.push(format!("QUALITIES is invalid: {e}"));
+                    vec![]
+                }
+            },
+            None => SUPPORTED_QUALITIES.to_vec(),
+        };
+
+        let redis = RedisConfig {
+            url: loader.required("REDIS_URL", file.redis.url),
+            pool_size: loader
+                .parse("REDIS_POOL_SIZE", file.redis.pool_size)
+                .unwrap_or(10),
+            connection_timeout: loader
+                .parse("REDIS_CONNECTION_TIMEOUT", file.redis.connection_timeout)
+                .unwrap_or(30),
+        };
+        if !redis.url.is_empty()
+            && let Err(e) = redis::Client::open(redis.url.as_str())
+        {
+            loader.errors.push(format!("REDIS_URL is invalid: {e}"));
+        }
+        if redis.pool_size == 0 {
+            loader
+                .errors
+                .push("REDIS_POOL_SIZE must be at least 1".to_string());
+        }
+
+        let s3 = S3Config {
+            endpoint: loader.required("S3_ENDPOINT", file.s3.endpoint),
+            region: loader.required("S3_REGION", file.s3.region),
+            bucket: loader.required("S3_BUCKET", file.s3.bucket),
+            access_key: loader.required("S3_ACCESS_KEY", file.s3.access_key),
+            secret_key: loader.required("S3_SECRET_KEY", file.s3.secret_key),
+            path_style: loader
+                .parse("S3_PATH_STYLE", file.s3.path_style)
+                .unwrap_or(false),
+        };
+        if !s3.endpoint.is_empty() && reqwest::Url::parse(&s3.endpoint).is_err() {
+            loader
+                .errors
+                .push(format!("S3_ENDPOINT is not a valid URL: {}", s3.endpoint));
+        }
+
+        let timeouts = Timeouts {
+            upstream: loader
+                .parse("UPSTREAM_TIMEOUT", file.timeouts.upstream)
+                .unwrap_or(10),
+            drain: loader
+                .parse("DRAIN_TIMEOUT", file.timeouts.drain)
+                .unwrap_or(25),
+            upstream_queue: loader
+                .parse("UPSTREAM_QUEUE_TIMEOUT", file.timeouts.upstream_queue)
+                .unwrap_or(5),
+        };
+
+        let cache = CachePolicy {
+            store_variants: loader
+                .parse("CACHE_STORE_VARIANTS", file.cache.store_variants)
+                .unwrap_or(true),
+            max_age: loader.parse("CACHE_MAX_AGE", file.cache.max_age),
+            batch_max_ids: loader
+                .parse("BATCH_MAX_IDS", file.cache.batch_max_ids)
+                .unwrap_or(100),
+            batch_concurrency: loader
+                .parse("BATCH_CONCURRENCY", file.cache.batch_concurrency)
+                .unwrap_or(8),
+        };
+        if cache.batch_concurrency == 0 {
+            loader
+                .errors
+                .push("BATCH_CONCURRENCY must be at least 1".to_string());
+        }
+
+        let admin = AdminConfig {
+            token: loader.env("ADMIN_TOKEN").or(file.admin.token),
+        };
+
+        let blocklist = BlocklistConfig {
+            status: loader
+                .parse("BLOCKLIST_STATUS", file.blocklist.status)
+                .unwrap_or(451),
+            file: loader.env("BLOCKLIST_FILE").or(file.blocklist.file),
+        };
+        if !(400..600).contains(&blocklist.status) {
+            loader.errors.push(format!(
+                "BLOCKLIST_STATUS must be an error status: {}",
+                blocklist.status
+            ));
+        }
+        if let Some(path) = &blocklist.file
+            && !std::path::Path::new(path).is_file()
+        {
+            loader
+                .errors
+                .push(format!("BLOCKLIST_FILE does not exist: {path}"));
+        }
+
+        let trusted_proxies = loader.list("TRUSTED_PROXIES", file.rate_limit.trusted_proxies);
+        let rate_limit = RateLimitConfig {
+            per_ip_per_second: loader
+                .parse("RATE_LIMIT_PER_IP", file.rate_limit.per_ip_per_second)
+                .unwrap_or(0.0),
+            per_ip_burst: loader
+                .parse("RATE_LIMIT_BURST", file.rate_limit.per_ip_burst)
+                .unwrap_or(10.0),
+            upstream_per_second: loader
+                .parse("UPSTREAM_RATE_LIMIT", file.rate_limit.upstream_per_second)
+                .unwrap_or(50.0),
+            upstream_concurrency: loader
+                .parse("UPSTREAM_CONCURRENCY", file.rate_limit.upstream_concurrency)
+                .unwrap_or(16),
+            trusted_proxies: match parse_networks(&trusted_proxies) {
+                Ok(networks) => networks,
+                Err(e) => {
+                    loader
+                        .errors
+                        .push(format!("TRUSTED_PROXIES is invalid: {e}"));
+                    vec![]
+                }
+            },
+        };
+        let rates = [
+            rate_limit.per_ip_per_second,
+            rate_limit.per_ip_burst,
+            rate_limit.upstream_per_second,
+        ];
+        if rates.iter().any(|rate| !rate.is_finite()) {
+            loader
+                .errors
+                .push("Rate limits must be finite numbers".to_string());
+        } else if rate_limit.per_ip_per_second < 0.0 || rate_limit.upstream_per_second < 0.0 {
+            loader
+                .errors
+                .push("Rate limits must not be negative".to_string());
+        }
+        if rate_limit.upstream_concurrency == 0 {
+            loader
+                .errors
+                .push("UPSTREAM_CONCURRENCY must be at least 1".to_string());
+        }
+        if rate_limit.per_ip_burst < 1.0 {
+            loader
+                .errors
+                .push("RATE_LIMIT_BURST must be at least 1".to_string());
+        }
+
+        let api_keys = ApiKeyConfig {
+            anonymous: loader
+                .parse("ALLOW_ANONYMOUS", file.api_keys.anonymous)
+                .unwrap_or(true),
+        };
+
+        let signing = SigningConfig {
+            secret: loader.env("SIGNING_SECRET").or(file.signing.secret),
+        };
+
+        let hotlink = HotlinkConfig {
+            cors_origins: loader.list("CORS_ORIGINS", file.hotlink.cors_origins),
+            allowed_hosts: loader.list("HOTLINK_ALLOWED_HOSTS", file.hotlink.allowed_hosts),
+            allow_missing: loader
+                .parse("HOTLINK_ALLOW_MISSING", file.hotlink.allow_missing)
+                .unwrap_or(true),
+            status: loader
+                .parse("HOTLINK_STATUS", file.hotlink.status)
+                .unwrap_or(406),
+        };
+        for origin in &hotlink.cors_origins {
+            if axum::http::HeaderValue::from_str(origin).is_err() || !origin.contains("://") {
+                loader
+                    .errors
+                    .push(format!("CORS_ORIGINS has an invalid origin: {origin}"));
+            }
+        }
+        if !(400..600).contains(&hotlink.status) {
+            loader.errors.push(format!(
+                "HOTLINK_STATUS must be an error status: {}",
+                hotlink.status
+            ));
+        }
+
+        let log = LogSettings {
+            format: loader
+                .env("LOG_FORMAT")
+                .or(file.log.format)
+                .unwrap_or_else(|| "human".to_string()),
+            level: loader
+                .env("LOG_LEVEL")
+                .or(file.log.level)
+                .unwrap_or_else(|| crate::log::default_level().to_string()),
+        };
+        if let Err(e) = LogConfig::new(&log.format, &log.level) {
+            loader.errors.push(e);
+        }
+
+        if !loader.errors.is_empty() {
+            return Err(ConfigErrors(loader.errors));
+        }
+        Ok(Config {
+            bind_address,
+            qualities,
+            redis,
+            s3,
+            timeouts,
+            cache,
+            admin,
+            blocklist,
+            rate_limit,
+            api_keys,
+            signing,
+            hotlink,
+            log,
+        })
+    }
+
+    pub fn upstream_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.upstream)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.drain)
+    }
+
+    pub fn upstream_queue_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.upstream_queue)
+    }
+
+    pub fn log_config(&self) -> LogConfig {
+        // Validated when the configuration was loaded
+        LogConfig::new(&self.log.format, &self.log.level).unwrap()
+    }
+
+    /// The effective configuration as TOML, with secrets redacted
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+        config.s3.secret_key = "<redacted>".to_string();
+        for secret in [&mut config.admin.token, &mut config.signing.secret] {
+            if secret.is_some() {
+                *secret = Some("<redacted>".to_string());
+            }
+        }
+        if let Ok(mut url) = reqwest::Url::parse(&config.redis.url)
+            && url.password().is_some()
+            && url.set_password(Some("redacted")).is_ok()
+        {
+            config.redis.url = url.to_string();
+        }
+        toml::to_string_pretty(&config).unwrap_or_else(|e| format!("# Error: {e}\n"))
+    }
+}
+
+/// Parse quality names like `maxresdefault.webp`, rejecting duplicates
+fn parse_qualities(names: &[String]) -> Result<Vec<Quality>, String> {
+    let mut qualities = vec![];
+    for name in names {
+        let quality = Quality::from_file_name(name).ok_or(format!("Unknown quality: {name}"))?;
+        if qualities.contains(&quality) {
+            return Err(format!("Duplicate quality: {name}"));
+        }
+        qualities.push(quality);
+    }
+    if qualities.is_empty() {
+        return Err("At least one quality is required".to_string());
+    }
+    Ok(qualities)
+}
+
+/// Parse addresses and CIDR ranges like `10.0.0.0/8`
+fn parse_networks(entries: &[String]) -> Result<Vec<IpNet>, String> {
+    entries
+        .iter()
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("Invalid address: {entry}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(file: Option<&str>, vars: &[(&str, &str)]) -> Result<Config, ConfigErrors> {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        Config::from_sources(file, |name| vars.get(name).cloned())
+    }
+
+    const FILE: &str = r#"
+        bind_address = "127.0.0.1:8080"
+        qualities = ["sddefault.jpg", "hqdefault.jpg"]
+
+        [redis]
+        url = "redis://:hunter2@localhost:6379"
+        pool_size = 4
+
+        [s3]
+        endpoint = "http://localhost:9000"
+        region = "unknown"
+        bucket = "thumbs"
+        access_key = "admin"
+        secret_key = "password123"
+        path_style = true
+
+        [cache]
+        max_age = 3600
+
+        [admin]
+        token = "s3cret"
+
+        [rate_limit]
+        trusted_proxies = ["10.0.0.0/8", "192.0.2.1"]
+
+        [log]
+        level = "warning"
+    "#;
+
+    #[test]
+    fn test_reports_all_errors() {
+        let errors = config(
+            None,
+            &[
+                ("REDIS_URL", "not a url"),
+                ("S3_ENDPOINT", "http://localhost:9000"),
+                ("S3_PATH_STYLE", "yes"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(errors.0.len(), 6);
+        assert!(errors.0.contains(&"S3_BUCKET is not set".to_string()));
+        assert!(
+            errors
+                .0
+                .iter()
+                .any(|e| e.starts_with("REDIS_URL is invalid"))
+        );
+        assert!(errors.0.iter().any(|e| e.starts_with("S3_PATH_STYLE")));
+    }
+
+    #[test]
+    fn test_file_with_env_overrides() {
+        let config = config(
+            Some(FILE),
+            &[("S3_BUCKET", "other"), ("REDIS_POOL_SIZE", "20")],
+        )
+        .unwrap();
+        assert_eq!(config.bind_address, "127.0.0.1:8080");
+        assert_eq!(config.qualities, vec![Quality::JpgSd, Quality::JpgHq]);
+        assert_eq!(config.redis.pool_size, 20);
+        assert_eq!(config.s3.bucket, "other");
+        assert!(config.s3.path_style);
+        assert_eq!(config.cache.max_age, Some(3600));
+        assert_eq!(config.timeouts.drain, 25);
+        assert_eq!(config.log.level, "warning");
+        assert_eq!(config.rate_limit.trusted_proxies.len(), 2);
+    }
+
+    #[test]
+    fn test_redacted() {
+        let printed = config(Some(FILE), &[]).unwrap().to_redacted_toml();
+        assert!(!printed.contains("hunter2"));
+        assert!(!printed.contains("password123"));
+        assert!(!printed.contains("s3cret"));
+        assert!(printed.contains("<redacted>"));
+    }
+
+    #[test]
+    fn test_non_finite_rate_limits() {
+        for (name, value) in [
+            ("RATE_LIMIT_PER_IP", "NaN"),
+            ("RATE_LIMIT_BURST", "inf"),
+            ("UPSTREAM_RATE_LIMIT", "-inf"),
+        ] {
+            let errors = config(Some(FILE), &[(name, value)]).unwrap_err();
+            assert_eq!(
+                errors.0,
+                vec!["Rate limits must be finite numbers".to_string()],
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        assert!(config(Some("unknown = 1"), &[]).is_err());
+        assert!(config(Some("qualities = [\"sddefault.png\"]"), &[]).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{AppState, log, log::LogType, storage};
+use axum::{Extension, Json, body::Body, http::Response, response::IntoResponse};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::atomic::Ordering, time::Duration};
+
+/// How long each dependency may take to respond before it is considered down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest wait between attempts to reach dependencies at startup
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new<E: std::fmt::Display>(result: Result<(), E>, latency: Duration) -> Self {
+        Check {
+            ok: result.is_ok(),
+            latency_ms: latency.as_millis(),
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    /// Whether dependencies have been reached since startup
+    started: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// The process is up and serving requests
+pub async fn liveness() -> impl IntoResponse {
+    Json(serde_json::json!({ "ok": true }))
+}
+
+/// Redis and S3 are reachable, so thumbnails can be served
+pub async fn readiness(Extension(state): Extension<AppState>) -> Response<Body> {
+    let (redis, s3) = tokio::join!(check_redis(&state), check_s3(&state));
+    let checks = BTreeMap::from([("redis", redis), ("s3", s3)]);
+    let started = state.started.load(Ordering::Relaxed);
+    let ready = started && checks.values().all(|check| check.ok);
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let readiness = Readiness {
+        ready,
+        started,
+        checks,
+    };
+    (status, Json(readiness)).into_response()
+}
+
+/// Retry reaching Redis and S3 with exponential backoff until both respond,
+/// then mark the server as started. Until then, readiness reports not ready.
+pub async fn wait_for_dependencies(state: AppState) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let (redis, s3) = tokio::join!(check_redis(&state), check_s3(&state));
+        if redis.ok && s3.ok {
+            state.started.store(true, Ordering::Relaxed);
+            log!("Connected to Redis and S3", LogType::Info);
+            return;
+        }
+        for (name, check) in [("Redis", redis), ("S3", s3)] {
+            if let Some(error) = check.error {
+                log!(
+                    "WARNING: {name} is not reachable, retrying in {}s: {error}",
+                    LogType::Warning,
+                    backoff.as_secs(),
+                );
+            }
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn check_redis(state: &AppState) -> Check {
+    let now = std::time::Instant::now();
+    // Redis calls block, so the ping runs off the runtime and alongside the
+    // S3 check
+    let pool = (*state.redis_pool).clone();
+    let ping = tokio::task::spawn_blocking(move || storage::ping_redis(&pool, CHECK_TIMEOUT));
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, ping).await {
+        Ok(Ok(result)) => result.map_err(|e| e.to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("Timed out".to_string()),
+    };
+    Check::new(result, now.elapsed())
+}
+
+async fn check_s3(state: &AppState) -> Check {
+    let now = std::time::Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, storage::ping_s3(&state.bucket)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err("Timed out".to_string()),
+    };
+    Check::new(result, now.elapsed())
+}
@@ -0,0 +1,105 @@
+use crate::config::HotlinkConfig;
+use axum::http::{
+    HeaderMap, HeaderValue,
+    header::{ORIGIN, REFERER},
+};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// CORS for the configured origins, or any origin if none are configured
+pub fn cors_layer(config: &HotlinkConfig) -> CorsLayer {
+    if config.cors_origins.is_empty() {
+        return CorsLayer::new().allow_origin(Any);
+    }
+    // Validated when the configuration was loaded
+    let origins = config
+        .cors_origins
+        .iter()
+        .map(|origin| HeaderValue::from_str(origin).unwrap())
+        .collect::<Vec<_>>();
+    CorsLayer::new().allow_origin(AllowOrigin::list(origins))
+}
+
+/// Host of the page embedding a thumbnail, from the `Origin` header or else
+/// the `Referer` header
+fn embedding_host(headers: &HeaderMap) -> Option<Option<String>> {
+    let value = headers
+        .get(ORIGIN)
+        .filter(|value| value.as_bytes() != b"null")
+        .or_else(|| headers.get(REFERER))?;
+    let host = value
+        .to_str()
+        .ok()
+        .and_then(|value| reqwest::Url::parse(value).ok())
+        .and_then(|url| url.host_str().map(str::to_lowercase));
+    Some(host)
+}
+
+/// Whether `host` matches an entry like `example.com`, or `*.example.com`
+/// for its subdomains
+fn host_matches(host: &str, pattern: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => host == pattern,
+    }
+}
+
+/// Whether the page making a request may embed thumbnails
+pub fn is_allowed(config: &HotlinkConfig, headers: &HeaderMap) -> bool {
+    if config.allowed_hosts.is_empty() {
+        return true;
+    }
+    match embedding_host(headers) {
+        None => config.allow_missing,
+        // A header that can't be parsed is never allowed
+        Some(None) => false,
+        Some(Some(host)) => config
+            .allowed_hosts
+            .iter()
+            .any(|pattern| host_matches(&host, pattern)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let config = HotlinkConfig {
+            cors_origins: vec![],
+            allowed_hosts: vec!["example.com".to_string(), "*.248.no".to_string()],
+            allow_missing: true,
+            status: 406,
+        };
+        let headers = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            headers
+        };
+        assert!(is_allowed(&config, &HeaderMap::new()));
+        assert!(is_allowed(
+            &config,
+            &headers(REFERER, "https://example.com/page")
+        ));
+        assert!(is_allowed(
+            &config,
+            &headers(ORIGIN, "https://thumbs.248.no")
+        ));
+        assert!(!is_allowed(&config, &headers(ORIGIN, "https://248.no")));
+        assert!(!is_allowed(
+            &config,
+            &headers(REFERER, "https://notexample.com/")
+        ));
+        assert!(!is_allowed(&config, &headers(REFERER, "not a url")));
+        assert!(!is_allowed(
+            &HotlinkConfig {
+                allow_missing: false,
+                ..config
+            },
+            &HeaderMap::new()
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{
+    AppState, json_error, log,
+    log::LogType,
+    quality::{Quality, Resolution},
+    storage,
+};
+use axum::{Extension, Json, body::Body, extract::Query, http::Response, response::IntoResponse};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// Number of IDs per page when a page size is not given
+const DEFAULT_PAGE_SIZE: usize = 1000;
+const MAX_PAGE_SIZE: usize = 10000;
+
+#[derive(Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Text,
+    Json,
+    Ndjson,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ListParams {
+    /// Cursor returned by the previous page, starting at 0
+    cursor: Option<u64>,
+    /// Approximate number of IDs per page
+    limit: Option<usize>,
+    /// Only list IDs starting with this prefix
+    prefix: Option<String>,
+    /// Only list IDs stored in this quality, e.g. `sddefault` or `sddefault.webp`
+    quality: Option<String>,
+    format: Option<Format>,
+}
+
+/// Filter on the stored quality of an entry
+enum QualityFilter {
+    Resolution(Resolution),
+    Quality(Quality),
+}
+
+impl QualityFilter {
+    fn parse(value: &str) -> Option<Self> {
+        match value.split_once('.') {
+            Some((slug, file_extension)) => {
+                Quality::from_parts(slug, file_extension).map(QualityFilter::Quality)
+            }
+            None => Resolution::from_slug(value).map(QualityFilter::Resolution),
+        }
+    }
+
+    fn matches(&self, quality: &Quality) -> bool {
+        match self {
+            QualityFilter::Resolution(resolution) => quality.resolution() == *resolution,
+            QualityFilter::Quality(q) => q == quality,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry {
+    id: String,
+    quality: Option<String>,
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Page {
+    /// Cursor for the next page, or null when there are no more entries
+    cursor: Option<String>,
+    entries: Vec<Entry>,
+}
+
+/// List cached video IDs using `SCAN`, so large caches can be paged through
+/// without blocking Redis. Without a cursor or limit, the plain text format
+/// returns every ID like it always has.
+pub async fn list_ids(
+    Query(params): Query<ListParams>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    let format = params.format.unwrap_or_default();
+    let paginated = params.cursor.is_some() || params.limit.is_some() || format != Format::Text;
+    let limit = match paginated {
+        true => params
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE),
+        false => usize::MAX,
+    };
+    let prefix = params.prefix.unwrap_or_default();
+    if !prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid prefix");
+    }
+    let quality_filter = match params.quality.as_deref().map(QualityFilter::parse) {
+        Some(None) => return json_error(StatusCode::BAD_REQUEST, "Invalid quality"),
+        Some(filter) => filter,
+        None => None,
+    };
+
+    let mut cursor = params.cursor.unwrap_or(0);
+    let mut entries = Vec::new();
+    loop {
+        let count = (limit - entries.len()).min(MAX_PAGE_SIZE);
+        let pattern = format!("{prefix}*");
+        let keys = match storage::scan_video_ids(&state.redis_pool, cursor, &pattern, count).await {
+            Ok((next, video_ids)) => {
+                cursor = next;
+                video_ids
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+            }
+            Err(e) => {
+                log!("ERROR: Error listing thumbnails: {e}", LogType::Error);
+                return json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error listing thumbnails",
+                );
+            }
+        };
+
+        if format == Format::Text && quality_filter.is_none() {
+            entries.extend(keys.into_iter().map(|id| Entry {
+                id,
+                quality: None,
+                format: None,
+            }));
+        } else {
+            let s3_keys = match storage::get_redis_objects(&state.redis_pool, &keys).await {
+                Ok(s3_keys) => s3_keys,
+                Err(e) => {
+                    log!("ERROR: Error listing thumbnails: {e}", LogType::Error);
+                    return json_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Error listing thumbnails",
+                    );
+                }
+            };
+            for (id, s3_key) in keys.into_iter().zip(s3_keys) {
+                let quality = s3_key.as_deref().and_then(Quality::from_s3_key);
+                if let Some(filter) = &quality_filter
+                    && !quality.is_some_and(|q| filter.matches(&q))
+                {
+                    continue;
+                }
+                entries.push(Entry {
+                    id,
+                    quality: quality.map(|q| q.slug().to_string()),
+                    format: quality.map(|q| q.file_extension().to_string()),
+                });
+            }
+        }
+
+        if cursor == 0 || entries.len() >= limit {
+            break;
+        }
+    }
+
+    let next_cursor = (cursor != 0).then(|| cursor.to_string());
+    let mut response = match format {
+        Format::Text => entries
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .into_response(),
+        Format::Json => Json(Page {
+            cursor: next_cursor.clone(),
+            entries,
+        })
+        .into_response(),
+        Format::Ndjson => {
+            let lines = entries
+                .iter()
+                .filter_map(|entry| serde_json::to_string(entry).ok())
+                .map(|line| line + "\n")
+                .collect::<String>();
+            ([("Content-Type", "application/x-ndjson")], lines).into_response()
+        }
+    };
+    if paginated {
+        let header = next_cursor.unwrap_or_else(|| "0".to_string());
+        if let Ok(value) = header.parse() {
+            response.headers_mut().insert("X-Next-Cursor", value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality_filter() {
+        let filter = QualityFilter::parse("sddefault").unwrap();
+        assert!(filter.matches(&Quality::WebpSd));
+        assert!(filter.matches(&Quality::JpgSd));
+        assert!(!filter.matches(&Quality::JpgHq));
+
+        let filter = QualityFilter::parse("maxresdefault.jpg").unwrap();
+        assert!(filter.matches(&Quality::JpgMaxres));
+        assert!(!filter.matches(&Quality::WebpMaxres));
+
+        assert!(QualityFilter::parse("maxresdefault.png").is_none());
+        assert!(QualityFilter::parse("default").is_none());
+    }
+}
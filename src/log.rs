@@ -1,4 +1,7 @@
-#[derive(PartialEq)]
+use crate::{quality::Quality, video_id::VideoId};
+use std::{io::Write, sync::OnceLock};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum LogType {
     Debug,
     Info,
@@ -7,29 +10,354 @@ pub enum LogType {
     Performance,
 }
 
-#[macro_export]
-macro_rules! log {
-    ($fmt:expr, $log_type:expr $(, $args:expr)* $(,)?) => {{
-        let ms = chrono::Local::now().timestamp_subsec_millis();
-        let format_str = format!("%Y-%m-%d %H:%M:%S.{:03}", ms);
-        let timestamp = chrono::Local::now().format(&format_str);
-
-        let color = match $log_type {
-            LogType::Debug => "\x1b[90m", // gray
-            LogType::Info => "\x1b[37m", // white
-            LogType::Warning => "\x1b[33m", // yellow
-            LogType::Error => "\x1b[31m", // red
-            LogType::Performance => "\x1b[32m", // green
+impl LogType {
+    fn name(&self) -> &'static str {
+        match self {
+            LogType::Debug => "debug",
+            LogType::Info => "info",
+            LogType::Warning => "warning",
+            LogType::Error => "error",
+            LogType::Performance => "performance",
+        }
+    }
+
+    /// Position in the order of verbosity, starting with the most verbose
+    fn severity(&self) -> u8 {
+        match self {
+            LogType::Performance => 0,
+            LogType::Debug => 1,
+            LogType::Info => 2,
+            LogType::Warning => 3,
+            LogType::Error => 4,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<LogType> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "performance" => Some(LogType::Performance),
+            "debug" => Some(LogType::Debug),
+            "info" => Some(LogType::Info),
+            "warning" | "warn" => Some(LogType::Warning),
+            "error" => Some(LogType::Error),
+            _ => None,
+        }
+    }
+}
+
+/// How log lines are written, set with `LOG_FORMAT`
+#[derive(Debug, PartialEq)]
+pub enum LogFormat {
+    /// Coloured free text, for local development
+    Human,
+    /// One JSON object per line, for log pipelines
+    Json,
+}
+
+/// Which log lines are written and how, decided once at startup
+#[derive(Debug)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Least severe level written, unless overridden for a module
+    pub level: LogType,
+    /// Levels for specific modules, e.g. `storage` or `list`
+    pub modules: Vec<(String, LogType)>,
+    /// Write every line to stderr, keeping stdout free for command output
+    pub stderr: bool,
+}
+
+impl LogConfig {
+    /// Build from a format, `human` or `json`, and a level spec: a default
+    /// level followed by per-module overrides, e.g. `info,storage=debug`
+    pub fn new(format: &str, level: &str) -> Result<Self, String> {
+        let format = match format {
+            "human" => LogFormat::Human,
+            "json" => LogFormat::Json,
+            _ => return Err(format!("Invalid log format: {format}")),
         };
+        let (level, modules, invalid) = parse_levels(level, LogType::Info);
+        if !invalid.is_empty() {
+            return Err(format!("Invalid log levels: {}", invalid.join(", ")));
+        }
+        Ok(LogConfig {
+            format,
+            level,
+            modules,
+            stderr: false,
+        })
+    }
+
+    /// Read `LOG_FORMAT` and `LOG_LEVEL` from the environment, ignoring
+    /// invalid values. Used until the configuration has been loaded.
+    pub fn from_env() -> Self {
+        let format = std::env::var("LOG_FORMAT").unwrap_or_else(|_| "human".to_string());
+        let level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| default_level().to_string());
+        LogConfig::new(&format, &level)
+            .or_else(|_| LogConfig::new("human", default_level()))
+            .unwrap()
+    }
+
+    fn enabled(&self, log_type: LogType, module: &str) -> bool {
+        // Modules are configured without the crate name
+        let module = module.split_once("::").map_or("", |(_, m)| m);
+        let level = self
+            .modules
+            .iter()
+            .filter(|(name, _)| {
+                module == name
+                    || module
+                        .strip_prefix(name.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.level, |(_, level)| *level);
+        log_type.severity() >= level.severity()
+    }
+}
+
+/// Level used when none is configured. `DEBUG=true` enables every level.
+pub fn default_level() -> &'static str {
+    match std::env::var("DEBUG").unwrap_or_default() == "true" {
+        true => "performance",
+        false => "info",
+    }
+}
+
+/// Parse a level spec like `info,storage=debug`, returning the default level,
+/// the per-module levels and any entries that could not be parsed
+fn parse_levels(spec: &str, default: LogType) -> (LogType, Vec<(String, LogType)>, Vec<String>) {
+    let mut level = default;
+    let mut modules = vec![];
+    let mut invalid = vec![];
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.split_once('=') {
+            Some((module, name)) => match LogType::from_name(name) {
+                Some(l) => modules.push((module.trim().to_string(), l)),
+                None => invalid.push(entry.to_string()),
+            },
+            None => match LogType::from_name(entry) {
+                Some(l) => level = l,
+                None => invalid.push(entry.to_string()),
+            },
+        }
+    }
+    (level, modules, invalid)
+}
+
+static LOG_CONFIG: OnceLock<LogConfig> = OnceLock::new();
+
+tokio::task_local! {
+    /// ID of the request being handled, included in every log line
+    static REQUEST_ID: String;
+}
+
+/// Run a future with a request ID attached to the log lines it writes
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+/// Keep the current request ID for a future that outlives the request, such
+/// as a spawned task
+pub fn in_current_request<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let request_id = REQUEST_ID.try_with(|id| id.clone()).ok();
+    async move {
+        match request_id {
+            Some(request_id) => REQUEST_ID.scope(request_id, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Set the logging configuration. Must be called before the first log line,
+/// otherwise the configuration is read from the environment.
+pub fn init(config: LogConfig) {
+    let _ = LOG_CONFIG.set(config);
+}
+
+fn config() -> &'static LogConfig {
+    LOG_CONFIG.get_or_init(LogConfig::from_env)
+}
 
-        let out: &mut dyn std::io::Write = match $log_type {
-            LogType::Debug | LogType::Info | LogType::Performance => &mut std::io::stdout(),
-            LogType::Warning | LogType::Error => &mut std::io::stderr(),
+/// Value of a structured field attached to a log line
+pub enum Field {
+    String(String),
+    Number(u64),
+}
+
+impl From<&str> for Field {
+    fn from(value: &str) -> Self {
+        Field::String(value.to_string())
+    }
+}
+
+impl From<&String> for Field {
+    fn from(value: &String) -> Self {
+        Field::String(value.clone())
+    }
+}
+
+impl From<String> for Field {
+    fn from(value: String) -> Self {
+        Field::String(value)
+    }
+}
+
+impl From<&Quality> for Field {
+    fn from(value: &Quality) -> Self {
+        Field::String(value.file_name())
+    }
+}
+
+impl From<Quality> for Field {
+    fn from(value: Quality) -> Self {
+        Field::from(&value)
+    }
+}
+
+impl From<&VideoId> for Field {
+    fn from(value: &VideoId) -> Self {
+        Field::from(&**value)
+    }
+}
+
+impl From<u64> for Field {
+    fn from(value: u64) -> Self {
+        Field::Number(value)
+    }
+}
+
+impl From<u128> for Field {
+    fn from(value: u128) -> Self {
+        Field::Number(value as u64)
+    }
+}
+
+impl From<usize> for Field {
+    fn from(value: usize) -> Self {
+        Field::Number(value as u64)
+    }
+}
+
+pub fn enabled(log_type: LogType, module: &str) -> bool {
+    config().enabled(log_type, module)
+}
+
+pub fn write(log_type: LogType, message: String, fields: &[(&str, Field)]) {
+    let now = chrono::Local::now();
+    let request_id = REQUEST_ID.try_with(|id| id.clone()).ok();
+    let line = match config().format {
+        LogFormat::Human => {
+            let timestamp = now.format("%Y-%m-%d %H:%M:%S%.3f");
+            let color = match log_type {
+                LogType::Debug => "\x1b[90m",       // gray
+                LogType::Info => "\x1b[37m",        // white
+                LogType::Warning => "\x1b[33m",     // yellow
+                LogType::Error => "\x1b[31m",       // red
+                LogType::Performance => "\x1b[32m", // green
+            };
+            match request_id {
+                Some(request_id) => {
+                    format!("{color}[{timestamp}] [{request_id}] {message}\x1b[0m")
+                }
+                None => format!("{color}[{timestamp}] {message}\x1b[0m"),
+            }
+        }
+        LogFormat::Json => json_line(&now.to_rfc3339(), &log_type, request_id, message, fields),
+    };
+
+    let out: &mut dyn Write = match log_type {
+        _ if config().stderr => &mut std::io::stderr(),
+        LogType::Debug | LogType::Info | LogType::Performance => &mut std::io::stdout(),
+        LogType::Warning | LogType::Error => &mut std::io::stderr(),
+    };
+    let _ = writeln!(out, "{line}");
+}
+
+fn json_line(
+    timestamp: &str,
+    log_type: &LogType,
+    request_id: Option<String>,
+    message: String,
+    fields: &[(&str, Field)],
+) -> String {
+    let mut object = serde_json::Map::new();
+    object.insert("timestamp".to_string(), timestamp.into());
+    object.insert("level".to_string(), log_type.name().into());
+    if let Some(request_id) = request_id {
+        object.insert("request_id".to_string(), request_id.into());
+    }
+    object.insert("message".to_string(), message.into());
+    for (key, value) in fields {
+        let value = match value {
+            Field::String(s) => s.as_str().into(),
+            Field::Number(n) => (*n).into(),
         };
+        object.insert(key.to_string(), value);
+    }
+    serde_json::Value::Object(object).to_string()
+}
 
-        // Only print performance logs if DEBUG is set to true
-        if $log_type != LogType::Performance || std::env::var("DEBUG").unwrap_or_default() == "true"{
-            let _ = writeln!(out, "{color}[{timestamp}] {}\x1b[0m", format!($fmt $(, $args)*));
+/// Write a log line. Structured fields for the JSON format can be given
+/// after a semicolon:
+///
+/// `log!("NEW: {video_id}", LogType::Info; video_id = video_id, cache = "miss")`
+#[macro_export]
+macro_rules! log {
+    ($fmt:expr, $log_type:expr $(, $args:expr)* $(,)? $(; $($key:ident = $value:expr),* $(,)?)?) => {{
+        let log_type = $log_type;
+        if $crate::log::enabled(log_type, module_path!()) {
+            $crate::log::write(
+                log_type,
+                format!($fmt $(, $args)*),
+                &[$($((stringify!($key), $crate::log::Field::from($value))),*)?],
+            );
         }
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let (level, modules, invalid) = parse_levels(
+            "warning, storage=debug,list=loud,stats=error",
+            LogType::Info,
+        );
+        let config = LogConfig {
+            format: LogFormat::Human,
+            level,
+            modules,
+            stderr: false,
+        };
+        assert_eq!(invalid, vec!["list=loud".to_string()]);
+        assert!(!config.enabled(LogType::Info, "thumbs_248_no"));
+        assert!(config.enabled(LogType::Warning, "thumbs_248_no"));
+        assert!(config.enabled(LogType::Debug, "thumbs_248_no::storage"));
+        assert!(!config.enabled(LogType::Performance, "thumbs_248_no::storage"));
+        assert!(!config.enabled(LogType::Warning, "thumbs_248_no::stats"));
+        assert!(!config.enabled(LogType::Info, "thumbs_248_no::storage_extra"));
+    }
+
+    #[test]
+    fn test_json_line() {
+        let line = json_line(
+            "2025-01-01T00:00:00+00:00",
+            &LogType::Info,
+            Some("42".to_string()),
+            "NEW: aGb3AlQrN9E".to_string(),
+            &[
+                ("video_id", Field::from("aGb3AlQrN9E")),
+                ("quality", Field::from(Quality::WebpMaxres)),
+                ("duration_ms", Field::from(12u64)),
+            ],
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "info");
+        assert_eq!(value["request_id"], "42");
+        assert_eq!(value["message"], "NEW: aGb3AlQrN9E");
+        assert_eq!(value["video_id"], "aGb3AlQrN9E");
+        assert_eq!(value["quality"], "maxresdefault.webp");
+        assert_eq!(value["duration_ms"], 12);
+    }
+}
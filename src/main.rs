@@ -1,39 +1,103 @@
 use crate::{
+    access::CacheOutcome,
+    apikeys::ApiKey,
+    config::Config,
     log::LogType,
-    quality::Quality,
+    metadata::{ImageInfo, Metadata, Variant},
+    quality::{Quality, Resolution},
+    ratelimit::RateLimiter,
+    stats::Stats,
     storage::{RedisPool, get_redis_object},
+    upstream::UpstreamQueue,
+    video_id::VideoId,
 };
 use anyhow::Result;
 use axum::{
-    Extension, Router,
+    Extension, Json, Router,
     body::{Body, Bytes},
-    extract::Path,
-    http::Response,
-    response::{Html, IntoResponse},
-    routing::get,
+    extract::{ConnectInfo, Path, Query, rejection::PathRejection},
+    http::{HeaderMap, HeaderValue, Response, Uri, header::RETRY_AFTER},
+    middleware,
+    response::{Html, IntoResponse, Redirect},
+    routing::{get, post},
 };
-use regex::Regex;
+use clap::Parser;
+use futures::stream::{self, StreamExt};
 use reqwest::StatusCode;
-use tower_http::cors::{Any, CorsLayer};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, atomic::AtomicBool},
+};
+use tokio::time::Instant;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::Instrument;
 
+mod access;
+mod admin;
+mod apikeys;
+mod blocklist;
+mod cli;
+mod config;
+mod health;
+mod hotlink;
+mod list;
 mod log;
+mod metadata;
+mod metrics;
 mod quality;
+mod ratelimit;
+mod shutdown;
+mod signing;
+mod stats;
 mod storage;
+mod telemetry;
+mod upstream;
+mod video_id;
+mod youtube;
 
 #[derive(Clone)]
 pub struct AppState {
     bucket: s3::Bucket,
     redis_pool: Box<RedisPool>,
+    stats: Arc<Stats>,
+    config: Arc<Config>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Client for requests to YouTube
+    http: reqwest::Client,
+    upstream: Arc<UpstreamQueue>,
+    /// Set once Redis and S3 have been reached after startup
+    started: Arc<AtomicBool>,
+    /// Cache writes running in the background, awaited on shutdown
+    cache_writes: TaskTracker,
 }
 impl AppState {
-    async fn new() -> Self {
-        let bucket = storage::s3_connection().await;
-        let redis_pool = storage::redis_pool().await;
-        AppState { bucket, redis_pool }
+    async fn new(config: &Config) -> Result<Self> {
+        let bucket = storage::s3_connection(&config.s3).await?;
+        let redis_pool = storage::redis_pool(&config.redis).await?;
+        let stats = Arc::new(Stats::new());
+        let http = reqwest::Client::builder()
+            .timeout(config.upstream_timeout())
+            .build()?;
+        Ok(AppState {
+            bucket,
+            redis_pool,
+            stats,
+            config: Arc::new(config.clone()),
+            rate_limiter: Arc::new(RateLimiter::new(&config.rate_limit)),
+            http,
+            upstream: Arc::new(UpstreamQueue::new(
+                config.rate_limit.upstream_concurrency,
+                config.upstream_queue_timeout(),
+            )),
+            started: Arc::new(AtomicBool::new(false)),
+            cache_writes: TaskTracker::new(),
+        })
     }
 }
 
-/// Supported qualities for thumbnails, in order of preference
+/// Supported qualities for thumbnails, in the default order of preference
 const SUPPORTED_QUALITIES: [Quality; 6] = [
     Quality::WebpMaxres,
     Quality::JpgMaxres,
@@ -50,103 +114,762 @@ fn s3_key(video_id: &str, quality: &Quality) -> String {
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
-    let app = Router::new()
+    let cli = cli::Cli::parse();
+    let config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in errors.0 {
+                log!("ERROR: {error}", LogType::Error);
+            }
+            std::process::exit(1);
+        }
+    };
+    if cli.print_config {
+        print!("{}", config.to_redacted_toml());
+        return;
+    }
+    let command = cli.command.unwrap_or(cli::Command::Serve);
+    let mut log_config = config.log_config();
+    log_config.stderr = !matches!(command, cli::Command::Serve);
+    log::init(log_config);
+    let tracer_provider = telemetry::init();
+
+    let state = match AppState::new(&config).await {
+        Ok(state) => state,
+        Err(e) => {
+            log!("ERROR: Error connecting to storage: {e}", LogType::Error);
+            std::process::exit(1);
+        }
+    };
+    let result = match command {
+        cli::Command::Serve => {
+            serve(state).await;
+            Ok(())
+        }
+        command => cli::run(command, &state).await,
+    };
+    if let Some(provider) = tracer_provider {
+        telemetry::shutdown(provider);
+    }
+    if let Err(e) = result {
+        log!("ERROR: {e}", LogType::Error);
+        std::process::exit(1);
+    }
+}
+
+/// Run the HTTP server until a shutdown signal is received
+async fn serve(state: AppState) {
+    let config = state.config.clone();
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            health::wait_for_dependencies(state.clone()).await;
+            if let Some(path) = &state.config.blocklist.file {
+                blocklist::load_file(&state, path).await;
+            }
+        }
+    });
+    let cache_writes = state.cache_writes.clone();
+
+    let mut app = Router::new()
         .route("/", get(index))
-        .route("/list", get(list_ids))
-        .route("/{video_id}", get(get_thumbnail))
-        .layer(Extension(AppState::new().await))
-        .layer(CorsLayer::new().allow_origin(Any));
+        .route("/stats", get(get_stats))
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(health::liveness))
+        .route("/readyz", get(health::readiness))
+        .merge(
+            Router::new()
+                .route("/list", get(list::list_ids))
+                .route("/batch", post(batch))
+                .route("/api/v1/thumbnails/{video_id}", get(get_thumbnail_metadata))
+                .route("/{video_id}", get(get_thumbnail))
+                .route("/vi/{video_id}/{file_name}", get(get_ytimg_thumbnail))
+                .route("/vi_webp/{video_id}/{file_name}", get(get_ytimg_thumbnail))
+                .route_layer(middleware::from_fn(apikeys::authenticate)),
+        );
+    if config.admin.token.is_some() {
+        app = app.nest("/admin", admin::router());
+    }
+    let app = app
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(Extension(state))
+        .layer(hotlink::cors_layer(&config.hotlink))
+        .layer(middleware::from_fn(access::access_log));
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:2342").await.unwrap();
+    let listener = match tokio::net::TcpListener::bind(&config.bind_address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log!(
+                "ERROR: Error listening on {}: {e}",
+                LogType::Error,
+                config.bind_address,
+            );
+            std::process::exit(1);
+        }
+    };
     log!(
         "Listening on http://{}",
-        LogType::Debug,
+        LogType::Info,
         listener.local_addr().unwrap(),
     );
-    axum::serve(listener, app).await.unwrap();
+    if config.rate_limit.per_ip_per_second == 0.0 {
+        log!(
+            "Per-IP rate limiting is disabled, set RATE_LIMIT_PER_IP to enable it",
+            LogType::Warning,
+        );
+    }
+
+    // Stop accepting connections on a signal, then give in-flight requests
+    // and cache writes until the drain deadline to complete
+    let stop = CancellationToken::new();
+    let mut server = tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(stop.clone().cancelled_owned())
+        .into_future(),
+    );
+    tokio::select! {
+        result = &mut server => {
+            log!("ERROR: Server stopped unexpectedly: {result:?}", LogType::Error);
+        }
+        _ = shutdown::signal() => {
+            stop.cancel();
+        }
+    }
+    let deadline = Instant::now() + config.drain_timeout();
+    if !server.is_finished()
+        && tokio::time::timeout_at(deadline, &mut server)
+            .await
+            .is_err()
+    {
+        log!(
+            "ERROR: Shutdown timed out, dropping in-flight requests",
+            LogType::Error
+        );
+    }
+    shutdown::drain_cache_writes(&cache_writes, deadline).await;
 }
 
-async fn index() -> Html<&'static str> {
-    Html(include_str!("../templates/index.html"))
+#[derive(Deserialize)]
+struct IndexParams {
+    /// YouTube URL or video ID to redirect to the thumbnail of
+    url: Option<String>,
 }
 
-async fn list_ids(Extension(state): Extension<AppState>) -> impl IntoResponse {
-    let keys = storage::list_redis_keys(&state.redis_pool).await;
-    if let Err(e) = keys {
-        log!("ERROR: Error listing thumbnails: {e}", LogType::Error);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Error listing thumbnails".to_string(),
-        );
+/// The form page, or a redirect to the thumbnail of the video in `url`
+async fn index(Query(params): Query<IndexParams>) -> Response<Body> {
+    let Some(url) = params.url else {
+        return Html(include_str!("../templates/index.html")).into_response();
+    };
+    match youtube::video_id_from_url(&url) {
+        Some(video_id) => Redirect::to(&format!("/{video_id}")).into_response(),
+        None => (StatusCode::BAD_REQUEST, "Not a YouTube URL or video ID").into_response(),
     }
-    let ids = keys.unwrap();
-    (StatusCode::OK, ids.join("\n"))
+}
+
+#[derive(Deserialize, Default)]
+struct ThumbnailParams {
+    /// Largest resolution to consider, e.g. `sddefault`
+    max: Option<String>,
+    /// Smallest resolution to consider, e.g. `sddefault`
+    min: Option<String>,
+}
+
+impl ThumbnailParams {
+    /// Whether anything but the default variant is requested
+    fn is_variant(&self) -> bool {
+        self.max.is_some() || self.min.is_some()
+    }
+
+    /// Path of this variant of a video on the proxy, like
+    /// `/aGb3AlQrN9E?max=sddefault`
+    fn proxy_path(&self, video_id: &str) -> String {
+        let query = [("max", &self.max), ("min", &self.min)]
+            .into_iter()
+            .filter_map(|(name, value)| Some(format!("{name}={}", value.as_ref()?)))
+            .collect::<Vec<_>>();
+        match query.is_empty() {
+            true => format!("/{video_id}"),
+            false => format!("/{video_id}?{}", query.join("&")),
+        }
+    }
+
+    /// Entries of `order` that fall within the requested range, in order of
+    /// preference
+    fn qualities(&self, order: &[Quality]) -> Result<Vec<Quality>, String> {
+        let parse = |value: &Option<String>| match value {
+            Some(slug) => Resolution::from_slug(slug)
+                .map(Some)
+                .ok_or(format!("Invalid resolution: {slug}")),
+            None => Ok(None),
+        };
+        let max = parse(&self.max)?;
+        let min = parse(&self.min)?;
+        let qualities = order
+            .iter()
+            .copied()
+            .filter(|q| max.is_none_or(|max| q.resolution() <= max))
+            .filter(|q| min.is_none_or(|min| q.resolution() >= min))
+            .collect::<Vec<_>>();
+        if qualities.is_empty() {
+            return Err("No supported quality in the requested range".to_string());
+        }
+        Ok(qualities)
+    }
+}
+
+/// Requests for variants other than the default must be signed when a
+/// signing secret is configured
+fn check_signature(
+    state: &AppState,
+    is_variant: bool,
+    uri: &Uri,
+) -> Result<(), signing::SignatureError> {
+    match &state.config.signing.secret {
+        Some(secret) if is_variant => {
+            let Query(query) = Query::<Vec<(String, String)>>::try_from_uri(uri)
+                .map_err(|_| signing::SignatureError::Invalid)?;
+            signing::verify(secret, uri.path(), &query, chrono::Utc::now().timestamp())
+        }
+        _ => Ok(()),
+    }
+}
+
+struct Thumbnail {
+    data: Bytes,
+    quality: Quality,
+    cache_hit: bool,
 }
 
 async fn get_thumbnail(
-    Path(video_id): Path<String>,
+    video_id: Result<Path<VideoId>, PathRejection>,
+    Query(params): Query<ThumbnailParams>,
+    uri: Uri,
     Extension(state): Extension<AppState>,
-) -> impl IntoResponse {
-    if !validate_video_id(&video_id) {
-        log!("NOT FOUND: Invalid video ID: {video_id}", LogType::Warning);
-        return fallback_response(400);
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    api_key: Option<Extension<ApiKey>>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let video_id = match video_id {
+        Ok(Path(video_id)) => video_id,
+        Err(rejection) => return invalid_video_id(&state, rejection),
+    };
+    let qualities = params
+        .qualities(&state.config.qualities)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))
+        .and_then(|qualities| {
+            check_signature(&state, params.is_variant(), &uri)
+                .map(|_| qualities)
+                .map_err(|e| (StatusCode::FORBIDDEN, e.to_string()))
+        });
+    let api_key = api_key.map(|Extension(api_key)| api_key);
+    serve_thumbnail(&state, &video_id, qualities, peer.ip(), api_key, &headers).await
+}
+
+/// Thumbnails at the paths used by `i.ytimg.com`, like
+/// `/vi/{video_id}/sddefault.jpg`, so the proxy can stand in for it with a
+/// host rewrite. Known file names are served in exactly that quality, if it
+/// is configured and, when signing is enabled, the URL is signed. Other file
+/// names, like `mqdefault.jpg`, get the default variant.
+async fn get_ytimg_thumbnail(
+    path: Result<Path<(VideoId, String)>, PathRejection>,
+    uri: Uri,
+    Extension(state): Extension<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    api_key: Option<Extension<ApiKey>>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let (video_id, file_name) = match path {
+        Ok(Path(path)) => path,
+        Err(rejection) => return invalid_video_id(&state, rejection),
+    };
+    let signed = check_signature(&state, true, &uri).is_ok();
+    let qualities = ytimg_qualities(&file_name, &state.config.qualities, signed);
+    let api_key = api_key.map(|Extension(api_key)| api_key);
+    serve_thumbnail(
+        &state,
+        &video_id,
+        Ok(qualities),
+        peer.ip(),
+        api_key,
+        &headers,
+    )
+    .await
+}
+
+/// Qualities to consider for an `i.ytimg.com` file name, among `order`
+fn ytimg_qualities(file_name: &str, order: &[Quality], signed: bool) -> Vec<Quality> {
+    match Quality::from_file_name(file_name) {
+        Some(quality) if signed && order.contains(&quality) => vec![quality],
+        _ => order.to_vec(),
+    }
+}
+
+/// Fallback for a thumbnail request with an invalid video ID
+fn invalid_video_id(state: &AppState, rejection: PathRejection) -> Response<Body> {
+    log!(
+        "NOT FOUND: {}",
+        LogType::Warning,
+        rejection.body_text();
+        cache = "fallback",
+    );
+    state.stats.record_fallback();
+    fallback_response(400)
+}
+
+/// Respond with the best thumbnail among `qualities`, or the fallback image
+/// with the status of whatever went wrong
+#[tracing::instrument(skip_all, fields(video_id = %video_id, quality, cache.hit))]
+async fn serve_thumbnail(
+    state: &AppState,
+    video_id: &VideoId,
+    qualities: Result<Vec<Quality>, (StatusCode, String)>,
+    peer: IpAddr,
+    api_key: Option<ApiKey>,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    // Blocked videos get their status whatever the embedding host
+    if let Some(status) = blocked_status(state, video_id).await {
+        state.stats.record_fallback();
+        return fallback_response(status.as_u16());
+    }
+    if !hotlink::is_allowed(&state.config.hotlink, headers) {
+        log!(
+            "HOTLINK: {video_id} - Embedding host is not allowed",
+            LogType::Warning;
+            video_id = video_id,
+            cache = "fallback",
+        );
+        state.stats.record_fallback();
+        return fallback_response(state.config.hotlink.status);
+    }
+    let qualities = match qualities {
+        Ok(qualities) => qualities,
+        Err((status, e)) => {
+            let reason = status.canonical_reason().unwrap_or_default();
+            log!(
+                "{}: {video_id} - {e}",
+                LogType::Warning,
+                reason.to_uppercase();
+                video_id = video_id,
+                cache = "fallback",
+            );
+            state.stats.record_fallback();
+            return fallback_response(status.as_u16());
+        }
+    };
+
+    let client = state.rate_limiter.client_ip(peer, headers);
+    match resolve_thumbnail(state, video_id, &qualities, Some(client), api_key.as_ref()).await {
+        Ok(thumbnail) => {
+            let span = tracing::Span::current();
+            span.record("quality", thumbnail.quality.file_name());
+            span.record("cache.hit", thumbnail.cache_hit);
+            image_response(
+                thumbnail.data,
+                &thumbnail.quality,
+                thumbnail.cache_hit,
+                state.config.cache.max_age,
+            )
+        }
+        Err(status) => {
+            state.stats.record_fallback();
+            let mut response = fallback_response(status.as_u16());
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let wait = match &api_key {
+                    Some(api_key)
+                        if apikeys::over_quota(&state.redis_pool, api_key)
+                            .await
+                            .unwrap_or(false) =>
+                    {
+                        apikeys::until_reset()
+                    }
+                    _ => state.rate_limiter.retry_after(Some(client)),
+                };
+                response.headers_mut().insert(
+                    RETRY_AFTER,
+                    HeaderValue::from(ratelimit::retry_after_secs(wait)),
+                );
+            }
+            response
+        }
     }
+}
+
+/// The cached best quality of a video and its position in `order`. A
+/// quality that is no longer configured counts as not cached, so the best
+/// configured one is fetched and replaces it.
+fn known_best(cached: Option<Quality>, order: &[Quality]) -> Option<(Quality, usize)> {
+    let cached = cached?;
+    let position = order.iter().position(|q| *q == cached)?;
+    Some((cached, position))
+}
+
+/// Find the best thumbnail for a video among `qualities`, which must be
+/// ordered like the configured qualities. A pinned quality is served whenever
+/// it is in range. The cached best quality is used to skip
+/// qualities known not to exist, and YouTube is only queried for variants that
+/// are not already stored. Requests to YouTube are rate limited, per `client`
+/// if given, and count against the quota of `api_key`. Either failing gives
+/// `TOO_MANY_REQUESTS`.
+#[tracing::instrument(skip(state, api_key))]
+async fn resolve_thumbnail(
+    state: &AppState,
+    video_id: &str,
+    qualities: &[Quality],
+    client: Option<IpAddr>,
+    api_key: Option<&ApiKey>,
+) -> Result<Thumbnail, StatusCode> {
+    let order = &state.config.qualities;
+    let restricted = qualities != order.as_slice();
 
     // If the image is already cached, return it
     let now = std::time::Instant::now();
-    let cached_data = match fetch_from_cache(&state.bucket, &state.redis_pool, &video_id).await {
-        Ok(data) => data,
-        Err(_) => {
-            return fallback_response(500);
-        }
+    let cached = match fetch_cached_quality(&state.redis_pool, video_id).await {
+        Ok(quality) => known_best(quality, order),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
     };
-    log!(
-        "CACHE READ: {video_id} - {}ms",
-        LogType::Performance,
-        now.elapsed().as_millis(),
-    );
-    if let Some((data, quality)) = cached_data {
-        log!("CACHE: {video_id} - {quality}", LogType::Debug);
-        return image_response(data, &quality, true);
-    }
-
-    let mut quality: Option<Quality> = None;
-    let mut body: Option<Bytes> = None;
-    for q in SUPPORTED_QUALITIES {
-        match fetch_thumbnail(&video_id, &q).await {
-            Ok(b) => {
-                body = Some(b);
-                quality = Some(q);
-                break;
+    let cached_quality = cached.map(|(quality, _)| quality);
+    let pinned = match metadata::get_pinned(&state.redis_pool, video_id).await {
+        Ok(pinned) => pinned,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    for quality in pinned.into_iter().chain(cached_quality) {
+        if !qualities.contains(&quality) {
+            continue;
+        }
+        let Some(data) = fetch_from_cache(&state.bucket, video_id, &quality).await else {
+            continue;
+        };
+        let duration_ms = now.elapsed().as_millis();
+        log!(
+            "CACHE READ: {video_id} - {duration_ms}ms",
+            LogType::Performance;
+            video_id = video_id,
+            duration_ms = duration_ms,
+        );
+        log!(
+            "CACHE: {video_id} - {quality}",
+            LogType::Debug;
+            video_id = video_id,
+            quality = quality,
+            cache = "hit",
+        );
+        state.stats.record_hit();
+        return Ok(Thumbnail {
+            data,
+            quality,
+            cache_hit: true,
+        });
+    }
+
+    // Qualities preferred over the cached best are known not to exist
+    let skip = cached.map_or(0, |(_, position)| position);
+    // Whether every quality before the current one has been ruled out, which
+    // makes the current one the best available for the video
+    let mut is_best = cached_quality.is_none();
+    let mut missed = false;
+    for q in &order[skip..] {
+        if !qualities.contains(q) {
+            is_best = false;
+            continue;
+        }
+        if restricted
+            && Some(*q) != cached_quality
+            && let Some(data) = fetch_from_cache(&state.bucket, video_id, q).await
+        {
+            log!(
+                "CACHE: {video_id} - {q}",
+                LogType::Debug;
+                video_id = video_id,
+                quality = q,
+                cache = "hit",
+            );
+            state.stats.record_hit();
+            return Ok(Thumbnail {
+                data,
+                quality: *q,
+                cache_hit: true,
+            });
+        }
+        if !missed {
+            if let Some(client) = client
+                && let Err(wait) = state.rate_limiter.check_client(client)
+            {
+                log!(
+                    "RATE LIMITED: {client} - {video_id} - retry in {}ms",
+                    LogType::Warning,
+                    wait.as_millis();
+                    video_id = video_id,
+                    client = client.to_string(),
+                );
+                return Err(StatusCode::TOO_MANY_REQUESTS);
+            }
+            if let Some(api_key) = api_key {
+                match apikeys::take_miss(&state.redis_pool, api_key).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        log!(
+                            "RATE LIMITED: API key {} used up its daily quota - {video_id}",
+                            LogType::Warning,
+                            api_key.id;
+                            video_id = video_id,
+                        );
+                        return Err(StatusCode::TOO_MANY_REQUESTS);
+                    }
+                    Err(e) => {
+                        log!("ERROR: Error counting API key usage: {e}", LogType::Error);
+                        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                    }
+                }
+            }
+            state.stats.record_miss();
+            missed = true;
+        }
+        if let Err(wait) = state.rate_limiter.check_upstream() {
+            log!(
+                "RATE LIMITED: Upstream limit reached - {video_id} - retry in {}ms",
+                LogType::Warning,
+                wait.as_millis();
+                video_id = video_id,
+            );
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        let now = std::time::Instant::now();
+        let result = fetch_thumbnail(state, video_id, q).await;
+        state.stats.record_upstream_latency(now.elapsed());
+        match result {
+            Ok(body) => {
+                let store =
+                    is_best || Some(*q) == cached_quality || state.config.cache.store_variants;
+                if store {
+                    save_to_cache(state, video_id, q, body.clone(), is_best).await;
+                }
+                log!(
+                    "NEW: {video_id} - {q}",
+                    LogType::Info;
+                    video_id = video_id,
+                    quality = q,
+                    cache = "miss",
+                );
+                return Ok(Thumbnail {
+                    data: body,
+                    quality: *q,
+                    cache_hit: false,
+                });
             }
             Err(e) => {
                 if e != StatusCode::NOT_FOUND {
-                    return fallback_response(e.as_u16());
+                    return Err(e);
                 }
-                continue;
             }
         }
     }
-    if body.is_none() || quality.is_none() {
-        return fallback_response(500);
+    match restricted {
+        true => Err(StatusCode::NOT_FOUND),
+        false => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
-    let body = body.unwrap();
-    let quality = quality.unwrap();
+}
 
-    save_to_cache(
-        state.bucket,
-        &state.redis_pool,
-        &video_id,
-        &quality,
-        body.clone(),
-    )
-    .await;
+async fn get_thumbnail_metadata(
+    video_id: Result<Path<VideoId>, PathRejection>,
+    Extension(state): Extension<AppState>,
+) -> Response<Body> {
+    let video_id = match video_id {
+        Ok(Path(video_id)) => video_id,
+        Err(rejection) => {
+            log!("NOT FOUND: {}", LogType::Warning, rejection.body_text());
+            return json_error(StatusCode::BAD_REQUEST, "Invalid video ID");
+        }
+    };
+    match thumbnail_metadata(&state, &video_id).await {
+        Ok(metadata) => Json(metadata).into_response(),
+        Err((status, message)) => json_error(status, message),
+    }
+}
+
+/// Describe the cached thumbnail of a video and its stored variants
+async fn thumbnail_metadata(
+    state: &AppState,
+    video_id: &str,
+) -> Result<Metadata, (StatusCode, &'static str)> {
+    let quality = match fetch_cached_quality(&state.redis_pool, video_id).await {
+        Ok(Some(quality)) => quality,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Thumbnail is not cached")),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error reading cache")),
+    };
+    let (fields, variants) = match tokio::try_join!(
+        metadata::get_metadata(&state.redis_pool, video_id),
+        metadata::get_variants(&state.redis_pool, video_id),
+    ) {
+        Ok(result) => result,
+        Err(e) => {
+            log!(
+                "ERROR: Error reading metadata of {video_id}: {e}",
+                LogType::Error;
+                video_id = video_id,
+            );
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error reading metadata"));
+        }
+    };
+
+    // Entries cached before metadata was recorded are described on first request
+    let mut fields = fields;
+    if !fields.contains_key("sha256")
+        && let Some(data) = fetch_from_cache(&state.bucket, video_id, &quality).await
+    {
+        let info = ImageInfo::from_data(&data);
+        if let Err(e) = metadata::save_metadata(&state.redis_pool, video_id, &info).await {
+            log!(
+                "ERROR: Error saving metadata of {video_id}: {e}",
+                LogType::Error;
+                video_id = video_id,
+            );
+        } else if let Ok(saved) = metadata::get_metadata(&state.redis_pool, video_id).await {
+            fields = saved;
+        }
+    }
+
+    let variants = SUPPORTED_QUALITIES
+        .iter()
+        .filter(|q| *q == &quality || variants.contains(&s3_key(video_id, q)))
+        .map(|q| Variant {
+            quality: q.slug().to_string(),
+            format: q.file_extension().to_string(),
+            url: format!("/vi/{video_id}/{}", q.file_name()),
+        })
+        .collect();
+
+    Ok(Metadata::new(video_id, &quality, &fields, variants))
+}
+
+fn json_error(status: StatusCode, message: &str) -> Response<Body> {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+    status: u16,
+    cache_hit: bool,
+    quality: Option<String>,
+    format: Option<String>,
+    url: Option<String>,
+}
 
-    log!("NEW: {video_id} - {quality}", LogType::Info);
-    image_response(body, &quality, false)
+impl BatchResult {
+    fn error(status: StatusCode) -> Self {
+        BatchResult {
+            status: status.as_u16(),
+            cache_hit: false,
+            quality: None,
+            format: None,
+            url: None,
+        }
+    }
+}
+
+/// Resolve many video IDs at once, returning where each thumbnail can be
+/// found rather than the images themselves
+async fn batch(
+    Query(params): Query<ThumbnailParams>,
+    Extension(state): Extension<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    api_key: Option<Extension<ApiKey>>,
+    headers: HeaderMap,
+    Json(request): Json<BatchRequest>,
+) -> Response<Body> {
+    let api_key = api_key.map(|Extension(api_key)| api_key);
+    let client = state.rate_limiter.client_ip(peer.ip(), &headers);
+    let max_ids = state.config.cache.batch_max_ids;
+    if request.ids.len() > max_ids {
+        return json_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("At most {max_ids} IDs are allowed per batch"),
+        );
+    }
+    let qualities = match params.qualities(&state.config.qualities) {
+        Ok(qualities) => qualities,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+    };
+    // A signature only covers the query, so it can't limit the IDs in the body
+    if state.config.signing.secret.is_some() && params.is_variant() {
+        return json_error(
+            StatusCode::FORBIDDEN,
+            "Variants can't be requested in batch lookups when signing is enabled",
+        );
+    }
+    let mut ids = request.ids;
+    ids.sort();
+    ids.dedup();
+    let results = stream::iter(ids)
+        .map(|video_id| {
+            let state = &state;
+            let qualities = &qualities;
+            let params = &params;
+            let api_key = api_key.as_ref();
+            async move {
+                if !VideoId::is_valid(&video_id) {
+                    return (video_id, BatchResult::error(StatusCode::BAD_REQUEST));
+                }
+                if let Some(status) = blocked_status(state, &video_id).await {
+                    return (video_id, BatchResult::error(status));
+                }
+                let result =
+                    match resolve_thumbnail(state, &video_id, qualities, Some(client), api_key)
+                        .await
+                    {
+                        Ok(thumbnail) => BatchResult {
+                            status: StatusCode::OK.as_u16(),
+                            cache_hit: thumbnail.cache_hit,
+                            quality: Some(thumbnail.quality.slug().to_string()),
+                            format: Some(thumbnail.quality.file_extension().to_string()),
+                            url: Some(params.proxy_path(&video_id)),
+                        },
+                        Err(status) => BatchResult::error(status),
+                    };
+                (video_id, result)
+            }
+        })
+        .buffer_unordered(state.config.cache.batch_concurrency)
+        .collect::<BTreeMap<_, _>>()
+        .await;
+
+    Json(results).into_response()
 }
 
-async fn fetch_thumbnail(video_id: &str, quality: &Quality) -> Result<Bytes, StatusCode> {
+async fn get_stats(Extension(state): Extension<AppState>) -> Response<Body> {
+    match stats::report(&state.redis_pool, Some(&state.stats)).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => {
+            log!("ERROR: Error reading stats: {e}", LogType::Error);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Error reading stats")
+        }
+    }
+}
+
+async fn get_metrics(Extension(state): Extension<AppState>) -> impl IntoResponse {
+    (
+        [("Content-Type", "text/plain; version=0.0.4")],
+        metrics::render(&state.redis_pool),
+    )
+}
+
+#[tracing::instrument(skip(state), fields(quality = %quality))]
+async fn fetch_thumbnail(
+    state: &AppState,
+    video_id: &str,
+    quality: &Quality,
+) -> Result<Bytes, StatusCode> {
+    let Some(_slot) = state.upstream.acquire(video_id).await else {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    };
     let now = std::time::Instant::now();
     let webp_postfix = if quality.file_extension() == "webp" {
         "_webp"
@@ -158,27 +881,37 @@ async fn fetch_thumbnail(video_id: &str, quality: &Quality) -> Result<Bytes, Sta
         quality.slug(),
         quality.file_extension()
     );
-    let response = match reqwest::get(&url).await {
+    let response = match state.http.get(&url).send().await {
         Ok(response) => response,
         Err(e) => {
             log!(
                 "ERROR: Error fetching {quality} thumbnail: {url}: {e}",
-                LogType::Error
+                LogType::Error;
+                video_id = video_id,
+                quality = quality,
             );
             return Err(e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
         }
     };
+    metrics::YOUTUBE_LATENCY
+        .with_label_values(&[quality.file_name()])
+        .observe(now.elapsed().as_secs_f64());
+    let duration_ms = now.elapsed().as_millis();
     log!(
-        "YOUTUBE FETCH: {quality} - {video_id} - {}ms",
-        LogType::Performance,
-        now.elapsed().as_millis(),
+        "YOUTUBE FETCH: {quality} - {video_id} - {duration_ms}ms",
+        LogType::Performance;
+        video_id = video_id,
+        quality = quality,
+        duration_ms = duration_ms,
     );
     if response.status() != StatusCode::OK {
         if response.status() != StatusCode::NOT_FOUND {
             log!(
                 "ERROR: Error fetching {quality} thumbnail for {video_id}: {}",
                 LogType::Error,
-                response.status(),
+                response.status();
+                video_id = video_id,
+                quality = quality,
             );
         }
         return Err(response.status());
@@ -189,68 +922,257 @@ async fn fetch_thumbnail(video_id: &str, quality: &Quality) -> Result<Bytes, Sta
         Err(e) => {
             log!(
                 "ERROR: Error reading response for {quality} thumbnail for {video_id}: {e}",
-                LogType::Error,
+                LogType::Error;
+                video_id = video_id,
+                quality = quality,
             );
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
+/// Store a thumbnail in the background, see `cache_thumbnail`
+#[tracing::instrument(skip(state, data), fields(quality = %quality))]
 async fn save_to_cache(
-    bucket: s3::Bucket,
-    redis_pool: &RedisPool,
+    state: &AppState,
     video_id: &str,
     quality: &Quality,
     data: Bytes,
+    is_best: bool,
 ) {
-    let key = s3_key(video_id, quality);
     let video_id = video_id.to_string();
-    let redis_pool = redis_pool.clone();
-    tokio::spawn(async move {
-        let result = storage::put_redis_object(&redis_pool, video_id.as_str(), &key).await;
+    let quality = *quality;
+    let cache_writes = state.cache_writes.clone();
+    let state = state.clone();
+    let task = async move {
+        // Errors are logged where they happen
+        let _ = cache_thumbnail(&state, &video_id, quality, data, is_best).await;
+    };
+    cache_writes.spawn(log::in_current_request(task.in_current_span()));
+}
+
+/// Store a thumbnail in S3, pointing Redis at it if it is the best available
+/// quality. Fails if nothing was stored, because the video is blocked or the
+/// S3 write failed.
+async fn cache_thumbnail(
+    state: &AppState,
+    video_id: &str,
+    quality: Quality,
+    data: Bytes,
+    is_best: bool,
+) -> Result<()> {
+    let key = s3_key(video_id, &quality);
+    let redis_pool = &state.redis_pool;
+    // A video may have been blocked while its thumbnail was fetched
+    if blocklist::is_blocked(redis_pool, video_id)
+        .await
+        .unwrap_or(true)
+    {
+        log!(
+            "BLOCKED: Not caching {video_id}",
+            LogType::Info;
+            video_id = video_id,
+        );
+        anyhow::bail!("{video_id} is blocked");
+    }
+    let write = async {
+        // The object is written before Redis points at it, so a failed
+        // write leaves the previous entry in place
+        let result = storage::put_s3_object(&state.bucket, &key, data.as_ref()).await;
         if let Err(e) = result {
             log!(
-                "ERROR: Error saving thumbnail to redis: {e}",
-                LogType::Error
+                "ERROR: Error saving thumbnail to s3: {e}",
+                LogType::Error;
+                video_id = video_id,
+                quality = quality,
             );
+            return Err(e.into());
+        }
+
+        // Only the best available quality is referenced from Redis, other
+        // variants are stored in S3 alone
+        let mut previous = None;
+        // Entry counts only change if the Redis pointer was replaced
+        let mut swapped = false;
+        if is_best {
+            let previous_bytes = metadata::get_metadata(redis_pool, video_id)
+                .await
+                .ok()
+                .and_then(|fields| fields.get("bytes")?.parse().ok())
+                .unwrap_or(0);
+            let result = storage::swap_redis_object(redis_pool, video_id, &key).await;
+            match result {
+                Ok(s3_id) => {
+                    swapped = true;
+                    previous = s3_id
+                        .as_deref()
+                        .and_then(Quality::from_s3_key)
+                        .map(|q| (q, previous_bytes));
+                }
+                Err(e) => {
+                    log!(
+                        "ERROR: Error saving thumbnail to redis: {e}",
+                        LogType::Error;
+                        video_id = video_id,
+                        quality = quality,
+                    );
+                }
+            }
         }
-        let result = storage::put_s3_object(&bucket, &key, data.as_ref()).await;
+
+        let result = metadata::save_variant(redis_pool, video_id, &key).await;
         if let Err(e) = result {
-            log!("ERROR: Error saving thumbnail to s3: {e}", LogType::Error);
+            log!(
+                "ERROR: Error saving variant of {video_id}: {e}",
+                LogType::Error;
+                video_id = video_id,
+                quality = quality,
+            );
         }
-    });
+        if is_best {
+            let info = ImageInfo::from_data(&data);
+            let result = match metadata::mark_first_cached(redis_pool, video_id).await {
+                Ok(()) => metadata::save_metadata(redis_pool, video_id, &info).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log!(
+                    "ERROR: Error saving metadata of {video_id}: {e}",
+                    LogType::Error;
+                    video_id = video_id,
+                );
+            }
+            if swapped {
+                let result =
+                    stats::record_entry(redis_pool, previous, &quality, info.bytes as u64).await;
+                if let Err(e) = result {
+                    log!("ERROR: Error updating stats: {e}", LogType::Error);
+                }
+            }
+        }
+        Ok(())
+    };
+    let result = write.await;
+    // A video blocked during the write may have been purged before the
+    // write landed, so remove whatever the write re-created
+    if let Ok(true) = blocklist::is_blocked(redis_pool, video_id).await {
+        log!(
+            "BLOCKED: Removing {video_id} cached while it was blocked",
+            LogType::Info;
+            video_id = video_id,
+        );
+        if let Err(e) = purge_thumbnail(state, video_id).await {
+            log!(
+                "ERROR: Error purging {video_id}: {e}",
+                LogType::Error;
+                video_id = video_id,
+            );
+        }
+        anyhow::bail!("{video_id} is blocked");
+    }
+    result
 }
 
-async fn fetch_from_cache(
-    bucket: &s3::Bucket,
-    redis_pool: &RedisPool,
-    video_id: &str,
-) -> Result<Option<(Vec<u8>, Quality)>> {
+/// Status to respond with if a video is on the blocklist, or if the blocklist
+/// can't be read
+async fn blocked_status(state: &AppState, video_id: &str) -> Option<StatusCode> {
+    match blocklist::is_blocked(&state.redis_pool, video_id).await {
+        Ok(false) => None,
+        Ok(true) => {
+            log!(
+                "BLOCKED: {video_id}",
+                LogType::Info;
+                video_id = video_id,
+                cache = "fallback",
+            );
+            let status = state.config.blocklist.status;
+            Some(StatusCode::from_u16(status).unwrap_or(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS))
+        }
+        Err(e) => {
+            log!(
+                "ERROR: Error reading blocklist: {e}",
+                LogType::Error;
+                video_id = video_id,
+            );
+            Some(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Best quality stored for a video, according to Redis
+#[tracing::instrument(skip(redis_pool))]
+async fn fetch_cached_quality(redis_pool: &RedisPool, video_id: &str) -> Result<Option<Quality>> {
     let s3_id = get_redis_object(redis_pool, video_id).await?;
-    if let Some(s3_id) = s3_id {
-        let quality = match Quality::from_s3_key(&s3_id) {
-            Some(quality) => quality,
+    match s3_id {
+        Some(s3_id) => match Quality::from_s3_key(&s3_id) {
+            Some(quality) => Ok(Some(quality)),
             None => {
                 log!("ERROR: Invalid S3 key: {s3_id}", LogType::Error);
-                return Err(anyhow::anyhow!("Invalid S3 key: {s3_id}"));
+                Err(anyhow::anyhow!("Invalid S3 key: {s3_id}"))
             }
-        };
-        let data = storage::get_s3_object(bucket, &s3_id).await;
-        if let Ok(data) = data {
-            return Ok(Some((data.into_bytes().to_vec(), quality)));
+        },
+        None => Ok(None),
+    }
+}
+
+/// Delete every stored variant of a video from S3 and forget it in Redis,
+/// returning the number of objects deleted
+#[tracing::instrument(skip(state))]
+async fn purge_thumbnail(state: &AppState, video_id: &str) -> Result<usize> {
+    let redis_pool = &state.redis_pool;
+    let cached_quality = fetch_cached_quality(redis_pool, video_id).await?;
+    let bytes = metadata::get_metadata(redis_pool, video_id)
+        .await?
+        .get("bytes")
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(0);
+    let mut keys = metadata::get_variants(redis_pool, video_id).await?;
+    if let Some(quality) = cached_quality {
+        let key = s3_key(video_id, &quality);
+        if !keys.contains(&key) {
+            keys.push(key);
         }
     }
-    Ok(None)
+    for key in &keys {
+        storage::delete_s3_object(&state.bucket, key).await?;
+    }
+    storage::delete_redis_keys(
+        redis_pool,
+        &[
+            video_id.to_string(),
+            metadata::metadata_key(video_id),
+            metadata::variants_key(video_id),
+        ],
+    )
+    .await?;
+    if let Some(quality) = cached_quality {
+        stats::remove_entry(redis_pool, &quality, bytes).await?;
+    }
+    Ok(keys.len())
 }
 
-fn image_response(data: impl Into<Body>, quality: &Quality, cache_hit: bool) -> Response<Body> {
+#[tracing::instrument(skip(bucket), fields(quality = %quality))]
+async fn fetch_from_cache(bucket: &s3::Bucket, video_id: &str, quality: &Quality) -> Option<Bytes> {
+    let data = storage::get_s3_object(bucket, &s3_key(video_id, quality)).await;
+    data.ok().map(|data| data.into_bytes())
+}
+
+fn image_response(
+    data: impl Into<Body>,
+    quality: &Quality,
+    cache_hit: bool,
+    max_age: Option<u64>,
+) -> Response<Body> {
     let content_type = match quality.file_extension() {
         "webp" => "image/webp",
         "jpg" => "image/jpeg",
         _ => panic!("Unsupported file extension: {}", quality.file_extension()),
     };
-    Response::builder()
-        .header("Content-Type", content_type)
+    let mut response = Response::builder().header("Content-Type", content_type);
+    if let Some(max_age) = max_age {
+        response = response.header("Cache-Control", format!("public, max-age={max_age}"));
+    }
+    response
         .header(
             "Cache-Status",
             match cache_hit {
@@ -258,6 +1180,10 @@ fn image_response(data: impl Into<Body>, quality: &Quality, cache_hit: bool) ->
                 false => "ThumbsCache; fwd=uri-miss; stored",
             },
         )
+        .extension(CacheOutcome(match cache_hit {
+            true => "hit",
+            false => "miss",
+        }))
         .body(data.into())
         .unwrap()
 }
@@ -267,16 +1193,24 @@ fn fallback_response(status: u16) -> Response<Body> {
     Response::builder()
         .status(status)
         .header("Content-Type", "image/webp")
+        .extension(CacheOutcome("fallback"))
         .body(Body::from(fallback_image.to_vec()))
         .unwrap()
 }
 
-/// Validate the video ID is a valid YouTube video ID
-///
-/// Source: https://wiki.archiveteam.org/index.php/YouTube/Technical_details
-fn validate_video_id(video_id: &str) -> bool {
-    let re = Regex::new(r"^[A-Za-z0-9_-]{10}[AEIMQUYcgkosw048]$").unwrap();
-    re.is_match(video_id)
+/// Lets `VideoId` be used in paths. Not in `video_id.rs`, which `populate`
+/// also compiles without serde.
+impl<'de> Deserialize<'de> for VideoId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for VideoId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self)
+    }
 }
 
 #[cfg(test)]
@@ -310,4 +1244,95 @@ mod tests {
             "aGb3AlQrN9E.hqdefault.jpg".to_string()
         );
     }
+
+    #[test]
+    fn test_quality_range() {
+        let params = |max: Option<&str>, min: Option<&str>| ThumbnailParams {
+            max: max.map(str::to_string),
+            min: min.map(str::to_string),
+        };
+        assert_eq!(
+            params(None, None).qualities(&SUPPORTED_QUALITIES).unwrap(),
+            SUPPORTED_QUALITIES.to_vec()
+        );
+        assert_eq!(
+            params(Some("sddefault"), None)
+                .qualities(&SUPPORTED_QUALITIES)
+                .unwrap(),
+            vec![
+                Quality::WebpSd,
+                Quality::JpgSd,
+                Quality::WebpHq,
+                Quality::JpgHq
+            ]
+        );
+        assert_eq!(
+            params(None, Some("sd"))
+                .qualities(&SUPPORTED_QUALITIES)
+                .unwrap(),
+            vec![
+                Quality::WebpMaxres,
+                Quality::JpgMaxres,
+                Quality::WebpSd,
+                Quality::JpgSd
+            ]
+        );
+        assert_eq!(
+            params(Some("hq"), Some("hqdefault"))
+                .qualities(&SUPPORTED_QUALITIES)
+                .unwrap(),
+            vec![Quality::WebpHq, Quality::JpgHq]
+        );
+        assert!(
+            params(Some("hq"), Some("maxres"))
+                .qualities(&SUPPORTED_QUALITIES)
+                .is_err()
+        );
+        assert!(
+            params(Some("mqdefault"), None)
+                .qualities(&SUPPORTED_QUALITIES)
+                .is_err()
+        );
+        assert_eq!(params(None, None).proxy_path("aGb3AlQrN9E"), "/aGb3AlQrN9E");
+        assert_eq!(
+            params(Some("sddefault"), Some("hq")).proxy_path("aGb3AlQrN9E"),
+            "/aGb3AlQrN9E?max=sddefault&min=hq"
+        );
+    }
+
+    #[test]
+    fn test_ytimg_qualities() {
+        assert_eq!(
+            ytimg_qualities("sddefault.jpg", &SUPPORTED_QUALITIES, true),
+            vec![Quality::JpgSd]
+        );
+        // Unknown names, unsigned requests and disabled qualities get the
+        // default variant
+        for (file_name, signed) in [
+            ("mqdefault.jpg", true),
+            ("hqdefault_live.jpg", true),
+            ("sddefault.jpg", false),
+        ] {
+            assert_eq!(
+                ytimg_qualities(file_name, &SUPPORTED_QUALITIES, signed),
+                SUPPORTED_QUALITIES.to_vec()
+            );
+        }
+        assert_eq!(
+            ytimg_qualities("sddefault.webp", &[Quality::JpgSd], true),
+            vec![Quality::JpgSd]
+        );
+    }
+
+    #[test]
+    fn test_known_best() {
+        let order = [Quality::WebpSd, Quality::JpgSd, Quality::JpgHq];
+        assert_eq!(
+            known_best(Some(Quality::JpgSd), &order),
+            Some((Quality::JpgSd, 1))
+        );
+        assert_eq!(known_best(None, &order), None);
+        // Cached before `WebpMaxres` was left out of the configured qualities
+        assert_eq!(known_best(Some(Quality::WebpMaxres), &order), None);
+    }
 }
@@ -0,0 +1,180 @@
+use crate::{quality::Quality, storage};
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Redis hash with details about the best stored thumbnail of a video
+pub fn metadata_key(video_id: &str) -> String {
+    format!("meta:{video_id}")
+}
+
+/// Redis set with the S3 keys of every stored variant of a video
+pub fn variants_key(video_id: &str) -> String {
+    format!("variants:{video_id}")
+}
+
+/// Details about a stored image, derived from its content
+pub struct ImageInfo {
+    pub bytes: usize,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub sha256: String,
+}
+
+impl ImageInfo {
+    pub fn from_data(data: &[u8]) -> Self {
+        let size = imagesize::blob_size(data).ok();
+        ImageInfo {
+            bytes: data.len(),
+            width: size.as_ref().map(|s| s.width),
+            height: size.as_ref().map(|s| s.height),
+            sha256: hex::encode(Sha256::digest(data)),
+        }
+    }
+
+    /// Fields to set, and those to remove since they are unknown for this
+    /// image
+    fn fields(&self) -> (Vec<(&'static str, String)>, Vec<&'static str>) {
+        let mut fields = vec![
+            ("bytes", self.bytes.to_string()),
+            ("sha256", self.sha256.clone()),
+        ];
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => {
+                fields.push(("width", width.to_string()));
+                fields.push(("height", height.to_string()));
+                (fields, vec![])
+            }
+            _ => (fields, vec!["width", "height"]),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Variant {
+    pub quality: String,
+    pub format: String,
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct Metadata {
+    pub video_id: String,
+    pub quality: String,
+    pub format: String,
+    pub bytes: Option<usize>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub sha256: Option<String>,
+    pub first_cached: Option<String>,
+    pub last_verified: Option<String>,
+    /// File name of the quality served by default in place of this one
+    pub pinned: Option<String>,
+    pub variants: Vec<Variant>,
+}
+
+impl Metadata {
+    pub fn new(
+        video_id: &str,
+        quality: &Quality,
+        fields: &HashMap<String, String>,
+        variants: Vec<Variant>,
+    ) -> Self {
+        let number = |name: &str| fields.get(name).and_then(|v| v.parse().ok());
+        Metadata {
+            video_id: video_id.to_string(),
+            quality: quality.slug().to_string(),
+            format: quality.file_extension().to_string(),
+            bytes: number("bytes"),
+            width: number("width"),
+            height: number("height"),
+            sha256: fields.get("sha256").cloned(),
+            first_cached: fields.get("first_cached").cloned(),
+            last_verified: fields.get("last_verified").cloned(),
+            pinned: fields.get("pinned").cloned(),
+            variants,
+        }
+    }
+}
+
+/// Record that a variant of a video is stored in S3
+pub async fn save_variant(pool: &storage::RedisPool, video_id: &str, s3_key: &str) -> Result<()> {
+    storage::add_redis_set_member(pool, &variants_key(video_id), s3_key).await
+}
+
+/// Forget a variant of a video once it is deleted from S3
+pub async fn remove_variant(pool: &storage::RedisPool, video_id: &str, s3_key: &str) -> Result<()> {
+    storage::remove_redis_set_member(pool, &variants_key(video_id), s3_key).await
+}
+
+/// Record details about the best stored thumbnail of a video, as verified now
+pub async fn save_metadata(
+    pool: &storage::RedisPool,
+    video_id: &str,
+    info: &ImageInfo,
+) -> Result<()> {
+    // Dimensions of a previous image must not outlive it
+    let (mut fields, removed) = info.fields();
+    fields.push(("last_verified", chrono::Utc::now().to_rfc3339()));
+    storage::update_redis_hash(pool, &metadata_key(video_id), &fields, &removed).await
+}
+
+/// Record when a video was first cached, unless already known
+pub async fn mark_first_cached(pool: &storage::RedisPool, video_id: &str) -> Result<()> {
+    let now = chrono::Utc::now().to_rfc3339();
+    storage::put_redis_hash_field_nx(pool, &metadata_key(video_id), "first_cached", &now).await
+}
+
+/// Serve a stored variant by default, in place of the best available
+/// quality the Redis pointer refers to
+pub async fn pin(pool: &storage::RedisPool, video_id: &str, quality: &Quality) -> Result<()> {
+    let fields = [("pinned", quality.file_name())];
+    storage::put_redis_hash(pool, &metadata_key(video_id), &fields).await
+}
+
+pub async fn unpin(pool: &storage::RedisPool, video_id: &str) -> Result<()> {
+    storage::update_redis_hash(pool, &metadata_key(video_id), &[], &["pinned"]).await
+}
+
+/// Quality a video is pinned to, if any
+pub async fn get_pinned(pool: &storage::RedisPool, video_id: &str) -> Result<Option<Quality>> {
+    let pinned = storage::get_redis_hash_field(pool, &metadata_key(video_id), "pinned").await?;
+    Ok(pinned.as_deref().and_then(Quality::from_file_name))
+}
+
+pub async fn get_metadata(
+    pool: &storage::RedisPool,
+    video_id: &str,
+) -> Result<HashMap<String, String>> {
+    storage::get_redis_hash(pool, &metadata_key(video_id)).await
+}
+
+pub async fn get_variants(pool: &storage::RedisPool, video_id: &str) -> Result<Vec<String>> {
+    storage::get_redis_set_members(pool, &variants_key(video_id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_info() {
+        let info = ImageInfo::from_data(include_bytes!("../fallback.webp"));
+        assert_eq!(info.bytes, 552);
+        assert_eq!(info.width, Some(120));
+        assert_eq!(info.height, Some(90));
+        assert_eq!(info.sha256.len(), 64);
+
+        let (fields, removed) = info.fields();
+        assert!(fields.iter().any(|(name, _)| *name == "width"));
+        assert!(removed.is_empty());
+
+        let info = ImageInfo::from_data(b"not an image");
+        assert_eq!(info.width, None);
+        assert_eq!(info.height, None);
+        let (fields, removed) = info.fields();
+        assert!(!fields.iter().any(|(name, _)| *name == "width"));
+        assert_eq!(removed, vec!["width", "height"]);
+    }
+}
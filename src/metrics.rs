@@ -0,0 +1,130 @@
+use crate::storage::RedisPool;
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request},
+    http::Response,
+    middleware::Next,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, TextEncoder, register_histogram,
+    register_histogram_vec, register_int_counter_vec, register_int_gauge,
+};
+use std::sync::LazyLock;
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "thumbs_http_requests_total",
+        "HTTP requests by route and status",
+        &["route", "status"]
+    )
+    .unwrap()
+});
+
+pub static CACHE_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "thumbs_cache_requests_total",
+        "Thumbnail lookups by outcome: hit, miss or fallback",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+pub static REDIS_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "thumbs_redis_duration_seconds",
+        "Duration of Redis commands",
+        &["command"]
+    )
+    .unwrap()
+});
+
+pub static S3_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "thumbs_s3_duration_seconds",
+        "Duration of S3 requests",
+        &["operation"]
+    )
+    .unwrap()
+});
+
+pub static YOUTUBE_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "thumbs_youtube_duration_seconds",
+        "Duration of thumbnail requests to YouTube by quality",
+        &["quality"]
+    )
+    .unwrap()
+});
+
+pub static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "thumbs_rate_limited_total",
+        "Requests to YouTube refused by a rate limit: client or upstream",
+        &["scope"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("thumbs_upstream_in_flight", "Requests to YouTube in flight").unwrap()
+});
+
+pub static UPSTREAM_QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "thumbs_upstream_queue_depth",
+        "Requests to YouTube waiting for a free slot"
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_QUEUE_WAIT: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "thumbs_upstream_queue_wait_seconds",
+        "Time requests to YouTube waited for a free slot"
+    )
+    .unwrap()
+});
+
+static REDIS_POOL_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "thumbs_redis_pool_connections",
+        "Connections held by the Redis pool"
+    )
+    .unwrap()
+});
+
+static REDIS_POOL_IDLE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "thumbs_redis_pool_idle_connections",
+        "Idle connections in the Redis pool"
+    )
+    .unwrap()
+});
+
+/// Count every request by the route it matched and the status returned
+pub async fn track_requests(request: Request, next: Next) -> Response<Body> {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let response = next.run(request).await;
+    HTTP_REQUESTS
+        .with_label_values(&[route.as_str(), response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Render all metrics in the Prometheus text format
+pub fn render(redis_pool: &RedisPool) -> String {
+    let state = redis_pool.state();
+    REDIS_POOL_CONNECTIONS.set(state.connections as i64);
+    REDIS_POOL_IDLE.set(state.idle_connections as i64);
+
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        return format!("# Error encoding metrics: {e}\n");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
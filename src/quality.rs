@@ -1,6 +1,7 @@
+use serde::{Serialize, Serializer};
 use std::fmt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Quality {
     WebpMaxres,
     JpgMaxres,
@@ -10,6 +11,28 @@ pub enum Quality {
     JpgHq,
 }
 
+/// Pixel size of a thumbnail, independent of its file format. Ordered from
+/// smallest to largest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resolution {
+    Hq,
+    Sd,
+    Maxres,
+}
+
+impl Resolution {
+    /// Parse a resolution from its YouTube slug, with or without the
+    /// `default` suffix (`sddefault` or `sd`)
+    pub fn from_slug(slug: &str) -> Option<Resolution> {
+        match slug.strip_suffix("default").unwrap_or(slug) {
+            "maxres" => Some(Resolution::Maxres),
+            "sd" => Some(Resolution::Sd),
+            "hq" => Some(Resolution::Hq),
+            _ => None,
+        }
+    }
+}
+
 impl Quality {
     pub fn file_extension(&self) -> &str {
         match self {
@@ -26,13 +49,34 @@ impl Quality {
         }
     }
 
+    /// File name of the thumbnail on YouTube, e.g. `maxresdefault.webp`
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.slug(), self.file_extension())
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        match self {
+            Quality::WebpMaxres | Quality::JpgMaxres => Resolution::Maxres,
+            Quality::WebpSd | Quality::JpgSd => Resolution::Sd,
+            Quality::WebpHq | Quality::JpgHq => Resolution::Hq,
+        }
+    }
+
     pub fn from_s3_key(key: &str) -> Option<Quality> {
         let parts = key.split('.').collect::<Vec<&str>>();
         if parts.len() != 3 {
             return None;
         }
-        let slug = parts[1];
-        let file_extension = parts[2];
+        Quality::from_parts(parts[1], parts[2])
+    }
+
+    /// Parse a file name like `maxresdefault.webp`
+    pub fn from_file_name(name: &str) -> Option<Quality> {
+        let (slug, file_extension) = name.split_once('.')?;
+        Quality::from_parts(slug, file_extension)
+    }
+
+    pub fn from_parts(slug: &str, file_extension: &str) -> Option<Quality> {
         match file_extension {
             "webp" => match slug {
                 "maxresdefault" => Some(Quality::WebpMaxres),
@@ -56,3 +100,9 @@ impl fmt::Display for Quality {
         write!(f, "{} {}", self.slug(), self.file_extension())
     }
 }
+
+impl Serialize for Quality {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.file_name())
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{config::RateLimitConfig, metrics};
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Number of tracked clients above which full buckets are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Allows `rate` requests per second on average, and bursts of up to `burst`
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(burst: f64, now: Instant) -> Self {
+        TokenBucket {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+
+    /// Take a token, or return how long until one is available
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, burst, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+    }
+
+    /// How long until a token is available, without taking one
+    fn wait(&self, rate: f64, burst: f64, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let tokens = (self.tokens + elapsed * rate).min(burst);
+        Duration::from_secs_f64(((1.0 - tokens) / rate).max(0.0))
+    }
+
+    fn is_full(&self, rate: f64, burst: f64, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * rate >= burst
+    }
+}
+
+/// Limits how often clients can trigger requests to YouTube. Cache hits are
+/// never limited.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    clients: Mutex<HashMap<IpAddr, TokenBucket>>,
+    upstream: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            config: config.clone(),
+            clients: Mutex::new(HashMap::new()),
+            upstream: Mutex::new(TokenBucket::new(config.upstream_per_second.max(1.0), now)),
+        }
+    }
+
+    /// Count a cache miss against a client, or return how long it must wait
+    pub fn check_client(&self, client: IpAddr) -> Result<(), Duration> {
+        let (rate, burst) = (self.config.per_ip_per_second, self.config.per_ip_burst);
+        if rate <= 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= PRUNE_THRESHOLD {
+            clients.retain(|_, bucket| !bucket.is_full(rate, burst, now));
+        }
+        let result = clients
+            .entry(client)
+            .or_insert_with(|| TokenBucket::new(burst, now))
+            .take(rate, burst, now);
+        if result.is_err() {
+            metrics::RATE_LIMITED.with_label_values(&["client"]).inc();
+        }
+        result
+    }
+
+    /// Count one request to YouTube, or return how long until one is allowed
+    pub fn check_upstream(&self) -> Result<(), Duration> {
+        let rate = self.config.upstream_per_second;
+        if rate <= 0.0 {
+            return Ok(());
+        }
+        let result = self
+            .upstream
+            .lock()
+            .unwrap()
+            .take(rate, rate.max(1.0), Instant::now());
+        if result.is_err() {
+            metrics::RATE_LIMITED.with_label_values(&["upstream"]).inc();
+        }
+        result
+    }
+
+    /// How long until a limited request from `client` could be retried
+    pub fn retry_after(&self, client: Option<IpAddr>) -> Duration {
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        let (rate, burst) = (self.config.per_ip_per_second, self.config.per_ip_burst);
+        if rate > 0.0
+            && let Some(bucket) = client.and_then(|ip| {
+                let clients = self.clients.lock().unwrap();
+                clients.get(&ip).map(|bucket| bucket.wait(rate, burst, now))
+            })
+        {
+            wait = wait.max(bucket);
+        }
+        let rate = self.config.upstream_per_second;
+        if rate > 0.0 {
+            let upstream = self.upstream.lock().unwrap();
+            wait = wait.max(upstream.wait(rate, rate.max(1.0), now));
+        }
+        wait
+    }
+
+    /// Address of the client that made a request. `X-Forwarded-For` is only
+    /// trusted when the request came through a trusted proxy, and is read
+    /// from the right, skipping further trusted proxies.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        client_ip(peer, headers, &self.config.trusted_proxies)
+    }
+}
+
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    if !trusted(&peer) {
+        return peer;
+    }
+    let forwarded = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|entry| entry.trim().parse::<IpAddr>())
+        .collect::<Vec<_>>();
+    let mut client = peer;
+    for entry in forwarded.into_iter().rev() {
+        match entry {
+            Ok(ip) => {
+                client = ip;
+                if !trusted(&ip) {
+                    break;
+                }
+            }
+            // Anything left of an unparseable entry can't be trusted
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+/// Value of a `Retry-After` header, in whole seconds
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, start);
+        assert!(bucket.take(1.0, 2.0, start).is_ok());
+        assert!(bucket.take(1.0, 2.0, start).is_ok());
+        let wait = bucket.take(1.0, 2.0, start).unwrap_err();
+        assert_eq!(retry_after_secs(wait), 1);
+        let later = start + Duration::from_millis(1500);
+        assert!(bucket.take(1.0, 2.0, later).is_ok());
+        assert!(bucket.take(1.0, 2.0, later).is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-For",
+            "203.0.113.9, 198.51.100.7, 10.0.0.2".parse().unwrap(),
+        );
+        let proxy = "10.0.0.1".parse().unwrap();
+        let direct = "192.0.2.1".parse().unwrap();
+        assert_eq!(
+            client_ip(proxy, &headers, &trusted),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_ip(direct, &headers, &trusted), direct);
+        assert_eq!(client_ip(proxy, &HeaderMap::new(), &trusted), proxy);
+    }
+}
@@ -0,0 +1,53 @@
+use crate::log::LogType;
+use tokio::time::Instant;
+use tokio_util::task::TaskTracker;
+
+/// Resolve when the process receives SIGINT or SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            crate::log!("ERROR: Error listening for SIGINT: {e}", LogType::Error);
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                crate::log!("ERROR: Error listening for SIGTERM: {e}", LogType::Error);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    crate::log!("Shutting down, draining in-flight requests", LogType::Info);
+}
+
+/// Wait for spawned cache writes to complete until `deadline`, logging any
+/// that are still pending as lost
+pub async fn drain_cache_writes(cache_writes: &TaskTracker, deadline: Instant) {
+    cache_writes.close();
+    if cache_writes.is_empty() {
+        return;
+    }
+    crate::log!(
+        "Waiting for {} pending cache writes",
+        LogType::Info,
+        cache_writes.len(),
+    );
+    if tokio::time::timeout_at(deadline, cache_writes.wait())
+        .await
+        .is_err()
+    {
+        crate::log!(
+            "ERROR: Shutdown timed out, {} pending cache writes are lost",
+            LogType::Error,
+            cache_writes.len(),
+        );
+    }
+}
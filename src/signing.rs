@@ -0,0 +1,197 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_PARAM: &str = "sig";
+pub const EXPIRES_PARAM: &str = "expires";
+
+/// Query parameters not covered by the signature. The API key identifies the
+/// caller, not the variant.
+const UNSIGNED_PARAMS: [&str; 2] = [SIGNATURE_PARAM, "key"];
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    Missing,
+    Invalid,
+    Expired,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "Signature is missing"),
+            SignatureError::Invalid => write!(f, "Signature is invalid"),
+            SignatureError::Expired => write!(f, "Signature has expired"),
+        }
+    }
+}
+
+/// Percent-encode everything but unreserved characters, so `&` and `=` in
+/// names and values can't be mistaken for separators
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// The signed message: the path followed by the signed query parameters,
+/// sorted so their order in the URL doesn't matter
+fn canonical(path: &str, params: &[(String, String)]) -> String {
+    let mut params = params
+        .iter()
+        .filter(|(name, _)| !UNSIGNED_PARAMS.contains(&name.as_str()))
+        .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+        .collect::<Vec<_>>();
+    params.sort();
+    format!("{path}?{}", params.join("&"))
+}
+
+fn mac(secret: &str, path: &str, params: &[(String, String)]) -> HmacSha256 {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(canonical(path, params).as_bytes());
+    mac
+}
+
+fn sign(secret: &str, path: &str, params: &[(String, String)]) -> String {
+    hex::encode(mac(secret, path, params).finalize().into_bytes())
+}
+
+/// Check the `sig` parameter of a request, and that the time in its
+/// `expires` parameter, if any, has not passed
+pub fn verify(
+    secret: &str,
+    path: &str,
+    params: &[(String, String)],
+    now: i64,
+) -> Result<(), SignatureError> {
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+    };
+    let signature = param(SIGNATURE_PARAM).ok_or(SignatureError::Missing)?;
+    let signature = hex::decode(signature).map_err(|_| SignatureError::Invalid)?;
+    mac(secret, path, params)
+        .verify_slice(&signature)
+        .map_err(|_| SignatureError::Invalid)?;
+    if let Some(expires) = param(EXPIRES_PARAM) {
+        let expires = expires
+            .parse::<i64>()
+            .map_err(|_| SignatureError::Invalid)?;
+        if expires < now {
+            return Err(SignatureError::Expired);
+        }
+    }
+    Ok(())
+}
+
+/// Path and query of a signed URL, valid until the Unix time `expires` if
+/// given. `params` must not contain the signature or expiry parameters.
+pub fn signed_url(
+    secret: &str,
+    path: &str,
+    mut params: Vec<(String, String)>,
+    expires: Option<i64>,
+) -> String {
+    if let Some(expires) = expires {
+        params.push((EXPIRES_PARAM.to_string(), expires.to_string()));
+    }
+    let signature = sign(secret, path, &params);
+    params.push((SIGNATURE_PARAM.to_string(), signature));
+    let mut url = reqwest::Url::parse("http://localhost").unwrap();
+    url.set_path(path);
+    url.query_pairs_mut().extend_pairs(&params);
+    format!("{}?{}", url.path(), url.query().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(query: &str) -> Vec<(String, String)> {
+        reqwest::Url::parse(&format!("http://localhost/?{query}"))
+            .unwrap()
+            .query_pairs()
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_signed_url() {
+        let url = signed_url(
+            "secret",
+            "/aGb3AlQrN9E",
+            vec![("max".to_string(), "sd".to_string())],
+            Some(1000),
+        );
+        let (path, query) = url.split_once('?').unwrap();
+        assert_eq!(path, "/aGb3AlQrN9E");
+        assert_eq!(verify("secret", path, &params(query), 999), Ok(()));
+        assert_eq!(
+            verify("secret", path, &params(query), 1001),
+            Err(SignatureError::Expired)
+        );
+        assert_eq!(
+            verify("other", path, &params(query), 999),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            verify("secret", "/VLM5ECY07nw", &params(query), 999),
+            Err(SignatureError::Invalid)
+        );
+
+        // Reordered parameters and an API key keep the signature valid
+        let (signature, rest) = query.split_once("&sig=").map(|(a, b)| (b, a)).unwrap();
+        let reordered = format!("sig={signature}&key=thumbs_abc&{rest}");
+        assert_eq!(verify("secret", path, &params(&reordered), 999), Ok(()));
+        let tampered = query.replace("max=sd", "max=maxres");
+        assert_eq!(
+            verify("secret", path, &params(&tampered), 999),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            verify("secret", path, &params("max=sd"), 999),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[test]
+    fn test_separators_in_values() {
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        // Both would be `max=sd&min=hq` if joined without encoding
+        let single = pairs(&[("max", "sd&min=hq")]);
+        let split = pairs(&[("max", "sd"), ("min", "hq")]);
+        assert_ne!(
+            canonical("/aGb3AlQrN9E", &single),
+            canonical("/aGb3AlQrN9E", &split)
+        );
+
+        let url = signed_url("secret", "/aGb3AlQrN9E", single, None);
+        let (path, query) = url.split_once('?').unwrap();
+        let mut reused = split;
+        reused.extend(
+            params(query)
+                .into_iter()
+                .filter(|(name, _)| name == SIGNATURE_PARAM),
+        );
+        assert_eq!(
+            verify("secret", path, &reused, 0),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(verify("secret", path, &params(query), 0), Ok(()));
+    }
+}
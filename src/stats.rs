@@ -0,0 +1,270 @@
+use crate::{SUPPORTED_QUALITIES, metadata, metrics, quality::Quality, s3_key, storage};
+use anyhow::Result;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Redis hash with the number of cached entries and their total size per
+/// quality, maintained as entries are stored
+const STATS_KEY: &str = "stats";
+
+/// Number of recent upstream fetches used to compute latency percentiles
+const LATENCY_SAMPLES: usize = 1024;
+
+fn count_field(quality: &Quality) -> String {
+    format!("count:{}", quality.file_name())
+}
+
+fn bytes_field(quality: &Quality) -> String {
+    format!("bytes:{}", quality.file_name())
+}
+
+/// Counters kept in memory since the server started
+pub struct Stats {
+    started_at: chrono::DateTime<chrono::Utc>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    fallbacks: AtomicU64,
+    upstream_latencies: Mutex<VecDeque<u64>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started_at: chrono::Utc::now(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            fallbacks: AtomicU64::new(0),
+            upstream_latencies: Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES)),
+        }
+    }
+
+    pub fn record_hit(&self) {
+        metrics::CACHE_REQUESTS.with_label_values(&["hit"]).inc();
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        metrics::CACHE_REQUESTS.with_label_values(&["miss"]).inc();
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_fallback(&self) {
+        metrics::CACHE_REQUESTS
+            .with_label_values(&["fallback"])
+            .inc();
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upstream_latency(&self, latency: Duration) {
+        let mut latencies = self.upstream_latencies.lock().unwrap();
+        if latencies.len() == LATENCY_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency.as_millis() as u64);
+    }
+
+    fn latency(&self) -> Latency {
+        let mut latencies = self
+            .upstream_latencies
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        latencies.sort_unstable();
+        Latency {
+            samples: latencies.len(),
+            p50: percentile(&latencies, 50),
+            p90: percentile(&latencies, 90),
+            p99: percentile(&latencies, 99),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+/// Update the per-quality counters when the best stored quality of a video
+/// changes. `previous` is the quality and size of the entry being replaced.
+pub async fn record_entry(
+    pool: &storage::RedisPool,
+    previous: Option<(Quality, u64)>,
+    quality: &Quality,
+    bytes: u64,
+) -> Result<()> {
+    let mut changes = vec![
+        (count_field(quality), 1),
+        (bytes_field(quality), bytes as i64),
+    ];
+    if let Some((previous, previous_bytes)) = previous {
+        changes.push((count_field(&previous), -1));
+        changes.push((bytes_field(&previous), -(previous_bytes as i64)));
+    }
+    storage::increment_redis_hash(pool, STATS_KEY, &changes).await
+}
+
+/// Update the per-quality counters when a video is removed from the cache
+pub async fn remove_entry(pool: &storage::RedisPool, quality: &Quality, bytes: u64) -> Result<()> {
+    let changes = vec![
+        (count_field(quality), -1),
+        (bytes_field(quality), -(bytes as i64)),
+    ];
+    storage::increment_redis_hash(pool, STATS_KEY, &changes).await
+}
+
+/// Size of the best stored thumbnail of a video, from its metadata or else
+/// from S3 for entries cached before metadata was recorded
+async fn entry_size(
+    pool: &storage::RedisPool,
+    bucket: &s3::Bucket,
+    video_id: &str,
+    quality: &Quality,
+) -> Result<u64> {
+    let fields = metadata::get_metadata(pool, video_id).await?;
+    if let Some(bytes) = fields.get("bytes").and_then(|bytes| bytes.parse().ok()) {
+        return Ok(bytes);
+    }
+    let size = storage::s3_object_size(bucket, &s3_key(video_id, quality)).await?;
+    Ok(size.unwrap_or(0))
+}
+
+/// Recount the entries and their size per quality by scanning every cached
+/// video, replacing the maintained counters. Needed once for entries cached
+/// before the counters existed. Returns the number of entries counted.
+pub async fn rebuild(pool: &storage::RedisPool, bucket: &s3::Bucket) -> Result<u64> {
+    let mut totals = BTreeMap::<String, i64>::new();
+    let mut entries = 0;
+    let mut cursor = 0;
+    loop {
+        let (next, video_ids) =
+            storage::scan_video_ids(pool, cursor, "*", storage::SCAN_COUNT).await?;
+        let keys = video_ids
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let s3_keys = storage::get_redis_objects(pool, &keys).await?;
+        let sized = stream::iter(keys.iter().zip(s3_keys))
+            .filter_map(|(video_id, s3_key)| async move {
+                let quality = Quality::from_s3_key(s3_key.as_deref()?)?;
+                Some(async move {
+                    let bytes = entry_size(pool, bucket, video_id, &quality).await?;
+                    anyhow::Ok((quality, bytes))
+                })
+            })
+            .buffer_unordered(16)
+            .try_collect::<Vec<_>>()
+            .await?;
+        for (quality, bytes) in sized {
+            *totals.entry(count_field(&quality)).or_default() += 1;
+            *totals.entry(bytes_field(&quality)).or_default() += bytes as i64;
+            entries += 1;
+        }
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    let fields = totals
+        .into_iter()
+        .map(|(field, value)| (field, value.to_string()))
+        .collect::<Vec<_>>();
+    storage::replace_redis_hash(pool, STATS_KEY, &fields).await?;
+    Ok(entries)
+}
+
+#[derive(Serialize)]
+struct QualityStats {
+    count: u64,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct Latency {
+    samples: usize,
+    p50: Option<u64>,
+    p90: Option<u64>,
+    p99: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SinceStart {
+    started_at: String,
+    hits: u64,
+    misses: u64,
+    fallbacks: u64,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    entries: u64,
+    qualities: BTreeMap<String, QualityStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since_start: Option<SinceStart>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_latency_ms: Option<Latency>,
+}
+
+/// Cache entries from Redis, along with the counters of this process if
+/// given. Only the server's own counters mean anything, so the CLI leaves
+/// them out.
+pub async fn report(pool: &storage::RedisPool, stats: Option<&Stats>) -> Result<Report> {
+    let fields = storage::get_redis_hash(pool, STATS_KEY).await?;
+    let value = |field: String| {
+        fields
+            .get(&field)
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(0)
+            .max(0) as u64
+    };
+    let qualities = SUPPORTED_QUALITIES
+        .iter()
+        .map(|q| {
+            let stats = QualityStats {
+                count: value(count_field(q)),
+                bytes: value(bytes_field(q)),
+            };
+            (q.file_name(), stats)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    Ok(Report {
+        entries: qualities.values().map(|q| q.count).sum(),
+        qualities,
+        since_start: stats.map(|stats| SinceStart {
+            started_at: stats.started_at.to_rfc3339(),
+            hits: stats.hits.load(Ordering::Relaxed),
+            misses: stats.misses.load(Ordering::Relaxed),
+            fallbacks: stats.fallbacks.load(Ordering::Relaxed),
+        }),
+        upstream_latency_ms: stats.map(Stats::latency),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        assert_eq!(percentile(&[], 50), None);
+        assert_eq!(percentile(&[7], 99), Some(7));
+        let values = (1..=100).collect::<Vec<_>>();
+        assert_eq!(percentile(&values, 50), Some(50));
+        assert_eq!(percentile(&values, 90), Some(90));
+        assert_eq!(percentile(&values, 99), Some(99));
+    }
+}
@@ -1,82 +1,380 @@
+use crate::{
+    config::{RedisConfig, S3Config},
+    metrics,
+    video_id::VideoId,
+};
 use anyhow::Result;
 use redis::Commands;
 use s3::{creds::Credentials, request::ResponseData};
-use std::boxed::Box;
+use std::{boxed::Box, collections::HashMap};
 
 pub type RedisPool = r2d2::Pool<redis::Client>;
 
-pub async fn redis_pool() -> Box<RedisPool> {
-    let url = std::env::var("REDIS_URL").expect("REDIS_URL is not set");
-    let client = redis::Client::open(url).expect("Failed to create Redis client");
+/// Keys Redis is asked to look at per `SCAN` call when walking every key
+pub const SCAN_COUNT: usize = 1000;
+
+/// Create the Redis connection pool. Connections are opened on demand, so
+/// this succeeds even if Redis is not reachable yet.
+pub async fn redis_pool(config: &RedisConfig) -> Result<Box<RedisPool>> {
+    let client = redis::Client::open(config.url.as_str())?;
     let pool = r2d2::Pool::builder()
-        .max_size(10)
-        .build(client)
-        .expect("Failed to create Redis connection pool");
-    Box::new(pool)
+        .max_size(config.pool_size)
+        .connection_timeout(std::time::Duration::from_secs(config.connection_timeout))
+        .build_unchecked(client);
+    Ok(Box::new(pool))
 }
 
-pub async fn put_redis_object(pool: &RedisPool, key: &str, value: &str) -> Result<()> {
-    let mut client = pool.get()?;
-    client.set::<&str, &str, ()>(key, value)?;
+/// Blocks the calling thread, so run it with `spawn_blocking`
+#[tracing::instrument(skip(pool), err)]
+pub fn ping_redis(pool: &RedisPool, timeout: std::time::Duration) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["ping"])
+        .start_timer();
+    let mut client = pool.get_timeout(timeout)?;
+    redis::cmd("PING").query::<String>(&mut *client)?;
     Ok(())
 }
 
+/// Set a key and return the value it replaced
+#[tracing::instrument(skip(pool), err)]
+pub async fn swap_redis_object(pool: &RedisPool, key: &str, value: &str) -> Result<Option<String>> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["set"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let result = redis::cmd("SET")
+        .arg(key)
+        .arg(value)
+        .arg("GET")
+        .query::<Option<String>>(&mut *client)?;
+    Ok(result)
+}
+
+#[tracing::instrument(skip(pool), err)]
 pub async fn get_redis_object(pool: &RedisPool, key: &str) -> Result<Option<String>> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["get"])
+        .start_timer();
     let mut client = pool.get()?;
     let result = client.get::<&str, Option<String>>(key)?;
     Ok(result)
 }
 
-pub async fn list_redis_keys(pool: &RedisPool) -> Result<Vec<String>> {
+#[tracing::instrument(skip(pool, fields), err)]
+pub async fn put_redis_hash(pool: &RedisPool, key: &str, fields: &[(&str, String)]) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["hset"])
+        .start_timer();
+    let mut client = pool.get()?;
+    client.hset_multiple::<&str, &str, String, ()>(key, fields)?;
+    Ok(())
+}
+
+/// Set a hash field only if it does not already exist
+#[tracing::instrument(skip(pool), err)]
+pub async fn put_redis_hash_field_nx(
+    pool: &RedisPool,
+    key: &str,
+    field: &str,
+    value: &str,
+) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["hsetnx"])
+        .start_timer();
+    let mut client = pool.get()?;
+    client.hset_nx::<&str, &str, &str, ()>(key, field, value)?;
+    Ok(())
+}
+
+/// Set some fields of a hash and remove others at once
+#[tracing::instrument(skip(pool, fields), err)]
+pub async fn update_redis_hash(
+    pool: &RedisPool,
+    key: &str,
+    fields: &[(&str, String)],
+    removed: &[&str],
+) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["hset"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if !removed.is_empty() {
+        pipe.hdel(key, removed).ignore();
+    }
+    if !fields.is_empty() {
+        pipe.hset_multiple(key, fields).ignore();
+    }
+    pipe.query::<()>(&mut *client)?;
+    Ok(())
+}
+
+/// Replace every field of a hash at once
+#[tracing::instrument(skip(pool, fields), err)]
+pub async fn replace_redis_hash(
+    pool: &RedisPool,
+    key: &str,
+    fields: &[(String, String)],
+) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["hset"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(key).ignore();
+    if !fields.is_empty() {
+        pipe.hset_multiple(key, fields).ignore();
+    }
+    pipe.query::<()>(&mut *client)?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool), err)]
+pub async fn get_redis_hash_field(
+    pool: &RedisPool,
+    key: &str,
+    field: &str,
+) -> Result<Option<String>> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["hget"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let result = client.hget::<&str, &str, Option<String>>(key, field)?;
+    Ok(result)
+}
+
+#[tracing::instrument(skip(pool), err)]
+pub async fn get_redis_hash(pool: &RedisPool, key: &str) -> Result<HashMap<String, String>> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["hgetall"])
+        .start_timer();
     let mut client = pool.get()?;
-    let result = client.keys::<&str, Vec<String>>("*")?;
+    let result = client.hgetall::<&str, HashMap<String, String>>(key)?;
     Ok(result)
 }
 
-fn s3_region() -> s3::Region {
+#[tracing::instrument(skip(pool, changes), err)]
+pub async fn increment_redis_hash(
+    pool: &RedisPool,
+    key: &str,
+    changes: &[(String, i64)],
+) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["hincrby"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let mut pipe = redis::pipe();
+    for (field, delta) in changes {
+        pipe.hincr(key, field, *delta).ignore();
+    }
+    pipe.query::<()>(&mut *client)?;
+    Ok(())
+}
+
+/// Add `delta` to a counter, returning its new value. The counter expires
+/// `ttl` after it was last changed.
+#[tracing::instrument(skip(pool), err)]
+pub async fn increment_redis_counter(
+    pool: &RedisPool,
+    key: &str,
+    delta: i64,
+    ttl: std::time::Duration,
+) -> Result<i64> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["incrby"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let (value,) = redis::pipe()
+        .atomic()
+        .incr(key, delta)
+        .expire(key, ttl.as_secs() as i64)
+        .ignore()
+        .query::<(i64,)>(&mut *client)?;
+    Ok(value)
+}
+
+#[tracing::instrument(skip(pool), err)]
+pub async fn add_redis_set_member(pool: &RedisPool, key: &str, member: &str) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["sadd"])
+        .start_timer();
+    let mut client = pool.get()?;
+    client.sadd::<&str, &str, ()>(key, member)?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool), err)]
+pub async fn get_redis_set_members(pool: &RedisPool, key: &str) -> Result<Vec<String>> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["smembers"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let result = client.smembers::<&str, Vec<String>>(key)?;
+    Ok(result)
+}
+
+#[tracing::instrument(skip(pool), err)]
+pub async fn remove_redis_set_member(pool: &RedisPool, key: &str, member: &str) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["srem"])
+        .start_timer();
+    let mut client = pool.get()?;
+    client.srem::<&str, &str, ()>(key, member)?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool), err)]
+pub async fn is_redis_set_member(pool: &RedisPool, key: &str, member: &str) -> Result<bool> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["sismember"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let result = client.sismember::<&str, &str, bool>(key, member)?;
+    Ok(result)
+}
+
+#[tracing::instrument(skip(pool, keys), fields(count = keys.len()), err)]
+pub async fn get_redis_objects(pool: &RedisPool, keys: &[String]) -> Result<Vec<Option<String>>> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["mget"])
+        .start_timer();
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut client = pool.get()?;
+    let result = client.mget::<&[String], Vec<Option<String>>>(keys)?;
+    Ok(result)
+}
+
+#[tracing::instrument(skip(pool), err)]
+pub async fn delete_redis_keys(pool: &RedisPool, keys: &[String]) -> Result<()> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["del"])
+        .start_timer();
+    let mut client = pool.get()?;
+    client.del::<&[String], ()>(keys)?;
+    Ok(())
+}
+
+/// One iteration of `SCAN`, returning the next cursor and the keys found.
+/// A returned cursor of 0 means the iteration is complete.
+#[tracing::instrument(skip(pool), err)]
+async fn scan_redis_keys(
+    pool: &RedisPool,
+    cursor: u64,
+    pattern: &str,
+    count: usize,
+) -> Result<(u64, Vec<String>)> {
+    let _timer = metrics::REDIS_LATENCY
+        .with_label_values(&["scan"])
+        .start_timer();
+    let mut client = pool.get()?;
+    let result = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(pattern)
+        .arg("COUNT")
+        .arg(count)
+        .query::<(u64, Vec<String>)>(&mut *client)?;
+    Ok(result)
+}
+
+/// Scan for keys matching `pattern` that are video IDs, which point at the
+/// best stored thumbnail. Redis also holds metadata about each video under
+/// prefixed keys, which are skipped.
+pub async fn scan_video_ids(
+    pool: &RedisPool,
+    cursor: u64,
+    pattern: &str,
+    count: usize,
+) -> Result<(u64, Vec<VideoId>)> {
+    let (next, keys) = scan_redis_keys(pool, cursor, pattern, count).await?;
+    let video_ids = keys
+        .into_iter()
+        .filter_map(|key| key.parse().ok())
+        .collect();
+    Ok((next, video_ids))
+}
+
+fn s3_region(config: &S3Config) -> s3::Region {
     s3::Region::Custom {
-        region: std::env::var("S3_REGION").expect("S3_REGION is not set"),
-        endpoint: std::env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set"),
+        region: config.region.clone(),
+        endpoint: config.endpoint.clone(),
     }
 }
 
-pub async fn s3_connection() -> s3::Bucket {
+pub async fn s3_connection(config: &S3Config) -> Result<s3::Bucket> {
     let credentials = Credentials {
-        access_key: Some(std::env::var("S3_ACCESS_KEY").expect("S3_ACCESS_KEY is not set")),
-        secret_key: Some(std::env::var("S3_SECRET_KEY").expect("S3_SECRET_KEY is not set")),
+        access_key: Some(config.access_key.clone()),
+        secret_key: Some(config.secret_key.clone()),
         expiration: None,
         security_token: None,
         session_token: None,
     };
-    let mut bucket = s3::Bucket::new(
-        &std::env::var("S3_BUCKET").expect("S3_BUCKET is not set"),
-        s3_region(),
-        credentials,
-    )
-    .expect("Failed to instantiate S3 bucket");
-
-    if std::env::var("S3_PATH_STYLE").unwrap_or_default() == "true" {
+    let mut bucket = s3::Bucket::new(&config.bucket, s3_region(config), credentials)?;
+
+    if config.path_style {
         bucket.set_path_style();
     }
-    *bucket
+    Ok(*bucket)
 }
 
+#[tracing::instrument(skip(bucket, content), err)]
 pub async fn put_s3_object(
     bucket: &s3::Bucket,
     key: &str,
     content: &[u8],
 ) -> Result<(), s3::error::S3Error> {
+    let _timer = metrics::S3_LATENCY
+        .with_label_values(&["put"])
+        .start_timer();
     bucket.put_object(key, content).await?;
     Ok(())
 }
 
+/// Cheap request against the bucket to check that it is reachable
+#[tracing::instrument(skip(bucket), err)]
+pub async fn ping_s3(bucket: &s3::Bucket) -> Result<(), s3::error::S3Error> {
+    let _timer = metrics::S3_LATENCY
+        .with_label_values(&["list"])
+        .start_timer();
+    bucket
+        .list_page(String::new(), None, None, None, Some(1))
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(bucket), err)]
 pub async fn get_s3_object(
     bucket: &s3::Bucket,
     key: &str,
 ) -> Result<ResponseData, s3::error::S3Error> {
+    let _timer = metrics::S3_LATENCY
+        .with_label_values(&["get"])
+        .start_timer();
     bucket.get_object(key).await
 }
 
+/// Size of an object in S3, without downloading it
+#[tracing::instrument(skip(bucket), err)]
+pub async fn s3_object_size(bucket: &s3::Bucket, key: &str) -> Result<Option<u64>> {
+    let _timer = metrics::S3_LATENCY
+        .with_label_values(&["head"])
+        .start_timer();
+    let (head, _) = bucket.head_object(key).await?;
+    Ok(head.content_length.map(|length| length.max(0) as u64))
+}
+
+#[tracing::instrument(skip(bucket), err)]
+pub async fn delete_s3_object(bucket: &s3::Bucket, key: &str) -> Result<(), s3::error::S3Error> {
+    let _timer = metrics::S3_LATENCY
+        .with_label_values(&["delete"])
+        .start_timer();
+    bucket.delete_object(key).await?;
+    Ok(())
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
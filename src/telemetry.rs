@@ -0,0 +1,59 @@
+use crate::log::LogType;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing_subscriber::layer::SubscriberExt;
+
+const SERVICE_NAME: &str = "thumbs-248-no";
+
+/// Export tracing spans over OTLP/HTTP if `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. Without either, spans are
+/// created but never recorded. The returned provider must be shut down before
+/// exiting to flush pending spans.
+pub fn init() -> Option<SdkTracerProvider> {
+    let enabled = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|var| std::env::var(var).is_ok_and(|v| !v.is_empty()));
+    if !enabled {
+        return None;
+    }
+
+    let exporter = match SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            crate::log!("ERROR: Error creating OTLP exporter: {e}", LogType::Error);
+            return None;
+        }
+    };
+    // `OTEL_SERVICE_NAME` takes precedence when set
+    let resource = match std::env::var("OTEL_SERVICE_NAME") {
+        Ok(_) => Resource::builder().build(),
+        Err(_) => Resource::builder().with_service_name(SERVICE_NAME).build(),
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME));
+    let subscriber = tracing_subscriber::registry().with(layer);
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        crate::log!(
+            "ERROR: Error installing tracing subscriber: {e}",
+            LogType::Error
+        );
+        return None;
+    }
+    crate::log!("Exporting traces over OTLP", LogType::Info);
+    Some(provider)
+}
+
+/// Flush pending spans
+pub fn shutdown(provider: SdkTracerProvider) {
+    if let Err(e) = provider.shutdown() {
+        crate::log!("ERROR: Error shutting down tracing: {e}", LogType::Error);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{log, log::LogType, metrics};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Bounds the number of requests to YouTube in flight. Further requests wait
+/// in a queue for up to `max_wait` before giving up.
+pub struct UpstreamQueue {
+    slots: Semaphore,
+    waiting: AtomicUsize,
+    max_wait: Duration,
+}
+
+/// Held while a request to YouTube is in flight
+pub struct Slot<'a> {
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        metrics::UPSTREAM_IN_FLIGHT.dec();
+    }
+}
+
+/// Counts a request as waiting for a slot until dropped, which also covers
+/// requests cancelled while waiting
+struct Waiting<'a> {
+    waiting: &'a AtomicUsize,
+}
+
+impl<'a> Waiting<'a> {
+    fn start(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        metrics::UPSTREAM_QUEUE_DEPTH.inc();
+        Waiting { waiting }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        metrics::UPSTREAM_QUEUE_DEPTH.dec();
+    }
+}
+
+impl UpstreamQueue {
+    pub fn new(concurrency: usize, max_wait: Duration) -> Self {
+        UpstreamQueue {
+            slots: Semaphore::new(concurrency),
+            waiting: AtomicUsize::new(0),
+            max_wait,
+        }
+    }
+
+    /// Wait for a free slot, or return `None` if none frees up in time
+    pub async fn acquire(&self, video_id: &str) -> Option<Slot<'_>> {
+        let now = std::time::Instant::now();
+        let waiting = Waiting::start(&self.waiting);
+        let result = tokio::time::timeout(self.max_wait, self.slots.acquire()).await;
+        drop(waiting);
+        let depth = self.waiting.load(Ordering::Relaxed);
+        let waited = now.elapsed();
+        metrics::UPSTREAM_QUEUE_WAIT.observe(waited.as_secs_f64());
+
+        match result {
+            Ok(Ok(permit)) => {
+                metrics::UPSTREAM_IN_FLIGHT.inc();
+                let wait_ms = waited.as_millis();
+                log!(
+                    "UPSTREAM QUEUE: {video_id} - waited {wait_ms}ms - {depth} waiting",
+                    LogType::Performance;
+                    video_id = video_id,
+                    duration_ms = wait_ms,
+                    queue_depth = depth,
+                );
+                Some(Slot { _permit: permit })
+            }
+            // The semaphore is never closed
+            Ok(Err(_)) => None,
+            Err(_) => {
+                log!(
+                    "UPSTREAM QUEUE: {video_id} - gave up after {}ms - {depth} waiting",
+                    LogType::Warning,
+                    self.max_wait.as_millis();
+                    video_id = video_id,
+                    queue_depth = depth,
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_gives_up() {
+        let queue = UpstreamQueue::new(1, Duration::from_millis(10));
+        let slot = queue.acquire("aGb3AlQrN9E").await;
+        assert!(slot.is_some());
+        assert!(queue.acquire("aGb3AlQrN9E").await.is_none());
+        drop(slot);
+        assert!(queue.acquire("aGb3AlQrN9E").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_wait_leaves_queue() {
+        let queue = UpstreamQueue::new(1, Duration::from_secs(10));
+        let _slot = queue.acquire("aGb3AlQrN9E").await;
+        let waiting = queue.acquire("aGb3AlQrN9E");
+        let cancelled = tokio::time::timeout(Duration::from_millis(10), waiting).await;
+        assert!(cancelled.is_err());
+        assert_eq!(queue.waiting.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,97 @@
+// Also compiled into `populate`, so this must only depend on `std` and `regex`
+use regex::Regex;
+use std::{fmt, ops::Deref, str::FromStr, sync::LazyLock};
+
+/// Ten characters of the URL-safe base64 alphabet, then one of the sixteen
+/// that leave the unused low bits of the 64-bit ID at zero
+///
+/// Source: https://wiki.archiveteam.org/index.php/YouTube/Technical_details
+static PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z0-9_-]{10}[AEIMQUYcgkosw048]$").unwrap());
+
+/// A valid YouTube video ID, like `aGb3AlQrN9E`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VideoId(String);
+
+impl VideoId {
+    pub fn is_valid(value: &str) -> bool {
+        PATTERN.is_match(value)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidVideoId(String);
+
+impl fmt::Display for InvalidVideoId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid video ID: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidVideoId {}
+
+impl FromStr for VideoId {
+    type Err = InvalidVideoId;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match VideoId::is_valid(value) {
+            true => Ok(VideoId(value.to_string())),
+            false => Err(InvalidVideoId(value.to_string())),
+        }
+    }
+}
+
+impl Deref for VideoId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for VideoId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_video_id() {
+        assert_eq!(
+            "aGb3AlQrN9E".parse::<VideoId>().as_deref(),
+            Ok("aGb3AlQrN9E")
+        );
+        assert!(VideoId::is_valid("_-_-_-_-_-w"));
+        assert_eq!(
+            "aGb3AlQrN9".parse::<VideoId>(),
+            Err(InvalidVideoId("aGb3AlQrN9".to_string()))
+        );
+        for invalid in [
+            "",
+            "aGb3AlQrN9EE",
+            "aGb3AlQrN9!",
+            "aGb3AlQr N9E",
+            " aGb3AlQrN9E",
+        ] {
+            assert!(!VideoId::is_valid(invalid), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_final_character() {
+        let alphabet = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let valid = alphabet
+            .chars()
+            .filter(|last| VideoId::is_valid(&format!("aGb3AlQrN9{last}")))
+            .collect::<String>();
+        // Only every fourth character of the alphabet
+        assert_eq!(valid, "AEIMQUYcgkosw048");
+        assert!(!VideoId::is_valid("aGb3AlQrN9e"));
+        assert!(!VideoId::is_valid("aGb3AlQrN9_"));
+        assert!(!VideoId::is_valid("aGb3AlQrN9-"));
+    }
+}
@@ -0,0 +1,88 @@
+use crate::video_id::VideoId;
+use reqwest::Url;
+
+/// Video ID in a YouTube URL, or the input itself if it is a video ID.
+/// URLs may leave out the scheme, like `youtu.be/aGb3AlQrN9E`.
+///
+/// - `https://www.youtube.com/watch?v=aGb3AlQrN9E`, also on `m.` and
+///   `music.youtube.com`
+/// - `https://www.youtube.com/shorts/aGb3AlQrN9E`, and `/embed/`, `/live/`
+///   and `/v/`
+/// - `https://youtu.be/aGb3AlQrN9E`
+/// - `https://i.ytimg.com/vi/aGb3AlQrN9E/maxresdefault.jpg`, and `/vi_webp/`
+pub fn video_id_from_url(input: &str) -> Option<VideoId> {
+    let input = input.trim();
+    if let Ok(video_id) = input.parse() {
+        return Some(video_id);
+    }
+    let url = match Url::parse(input) {
+        Ok(url) if url.has_host() => url,
+        _ => Url::parse(&format!("https://{input}")).ok()?,
+    };
+    let host = url.host_str()?.to_lowercase();
+    let is_host = |domain: &str| host == domain || host.ends_with(&format!(".{domain}"));
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+
+    let video_id = if is_host("youtu.be") {
+        segments.next()?
+    } else if is_host("youtube.com") || is_host("youtube-nocookie.com") {
+        match segments.next()? {
+            "watch" => {
+                return url
+                    .query_pairs()
+                    .find(|(name, _)| name == "v")
+                    .and_then(|(_, value)| value.parse().ok());
+            }
+            "shorts" | "embed" | "live" | "v" => segments.next()?,
+            _ => return None,
+        }
+    } else if is_host("ytimg.com") {
+        match segments.next()? {
+            "vi" | "vi_webp" => segments.next()?,
+            _ => return None,
+        }
+    } else {
+        return None;
+    };
+    video_id.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_video_id_from_url() {
+        let id = "aGb3AlQrN9E".parse().ok();
+        for input in [
+            "aGb3AlQrN9E",
+            " aGb3AlQrN9E ",
+            "https://www.youtube.com/watch?v=aGb3AlQrN9E",
+            "https://www.youtube.com/watch?feature=share&v=aGb3AlQrN9E&t=42",
+            "youtube.com/watch?v=aGb3AlQrN9E",
+            "https://m.youtube.com/watch?v=aGb3AlQrN9E",
+            "https://music.youtube.com/watch?v=aGb3AlQrN9E&list=RDAMVM",
+            "https://www.youtube.com/shorts/aGb3AlQrN9E",
+            "https://www.youtube.com/embed/aGb3AlQrN9E?start=10",
+            "https://www.youtube-nocookie.com/embed/aGb3AlQrN9E",
+            "https://www.youtube.com/live/aGb3AlQrN9E?si=abc",
+            "https://youtu.be/aGb3AlQrN9E",
+            "youtu.be/aGb3AlQrN9E?t=1",
+            "https://i.ytimg.com/vi/aGb3AlQrN9E/maxresdefault.jpg",
+            "https://i9.ytimg.com/vi_webp/aGb3AlQrN9E/mqdefault.webp?sqp=abc",
+        ] {
+            assert_eq!(video_id_from_url(input), id, "{input}");
+        }
+        for input in [
+            "",
+            "aGb3AlQrN9F",
+            "https://www.youtube.com/watch?v=aGb3AlQrN9F",
+            "https://www.youtube.com/@channel",
+            "https://notyoutube.com/watch?v=aGb3AlQrN9E",
+            "https://example.com/aGb3AlQrN9E",
+            "https://youtu.be/",
+        ] {
+            assert_eq!(video_id_from_url(input), None, "{input}");
+        }
+    }
+}